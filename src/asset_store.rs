@@ -0,0 +1,430 @@
+use crate::body_hash::codings_name;
+use crate::range::RangeWindow;
+use hyper::body::{Bytes, Sender};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// Size of the chunks read from a stored body.
+const READ_CHUNK_SIZE: usize = 256 * 1024;
+// Extension of the bodies being written, removed when the store is opened.
+const TEMP_EXTENSION: &str = "tmp";
+
+/// Disk store of the verified bodies of the streamed assets, content-addressed by the
+/// certified sha256 of the decoded body, the `Content-Encoding` and the size of the stored
+/// bytes: the same content encoded twice with a different compression has two bodies.
+/// The least recently used bodies are removed when the store is over its maximum size.
+pub struct AssetStore {
+    dir: PathBuf,
+    max_size: u64,
+    entries: Mutex<AssetStoreEntries>,
+    //used to name the temporary files of the concurrent writers.
+    temp_count: AtomicU64,
+}
+
+#[derive(Default)]
+struct AssetStoreEntries {
+    files: HashMap<String, StoredFile>,
+    size: u64,
+    //incremented at each access, used to find the least recently used file.
+    tick: u64,
+}
+
+struct StoredFile {
+    size: u64,
+    last_used: u64,
+}
+
+impl AssetStore {
+    /// Open the store in `dir`, created if needed. The stored bodies are kept, from the
+    /// least to the most recently modified. The files not named by the store are ignored.
+    pub fn open(dir: &Path, max_size: u64) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) if metadata.is_file() => name,
+                _ => continue,
+            };
+            if is_temp_name(&name) {
+                std::fs::remove_file(entry.path())?;
+                continue;
+            }
+            if !is_file_name(&name) {
+                continue;
+            }
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            files.push((modified, name, metadata.len()));
+        }
+        files.sort();
+
+        let mut entries = AssetStoreEntries::default();
+        for (_, name, size) in files {
+            entries.insert(name, size);
+        }
+        for path in entries.evict(max_size) {
+            std::fs::remove_file(dir.join(path))?;
+        }
+        Ok(AssetStore {
+            dir: dir.to_path_buf(),
+            max_size,
+            entries: Mutex::new(entries),
+            temp_count: AtomicU64::new(0),
+        })
+    }
+
+    /// The path of the stored body with this sha256, encoding and size.
+    pub fn get(&self, sha: &[u8], encoding: Option<&str>, size: u64) -> Option<PathBuf> {
+        let name = file_name(sha, encoding, size)?;
+        let mut entries = self.entries.lock().unwrap();
+        entries.tick += 1;
+        let tick = entries.tick;
+        let file = entries.files.get_mut(&name)?;
+        file.last_used = tick;
+        Some(self.dir.join(name))
+    }
+
+    /// A writer of the body with this sha256, encoding and size, stored once `commit` is
+    /// called after the hash of the written body is checked.
+    pub async fn writer(
+        self: &Arc<Self>,
+        sha: &[u8],
+        encoding: Option<&str>,
+        size: u64,
+    ) -> io::Result<AssetWriter> {
+        if size > self.max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("body is larger than {} bytes", self.max_size),
+            ));
+        }
+        let name = file_name(sha, encoding, size).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Unsupported Content-Encoding '{}'",
+                    encoding.unwrap_or_default()
+                ),
+            )
+        })?;
+        let temp_path = self.dir.join(temp_name(
+            &name,
+            self.temp_count.fetch_add(1, Ordering::Relaxed),
+        ));
+        let file = tokio::fs::File::create(&temp_path).await?;
+        Ok(AssetWriter {
+            store: self.clone(),
+            name,
+            temp_path,
+            file,
+            size: 0,
+            expected_size: size,
+            committed: false,
+        })
+    }
+}
+
+impl AssetStoreEntries {
+    fn insert(&mut self, name: String, size: u64) {
+        self.tick += 1;
+        let file = StoredFile {
+            size,
+            last_used: self.tick,
+        };
+        if let Some(previous) = self.files.insert(name, file) {
+            self.size -= previous.size;
+        }
+        self.size += size;
+    }
+
+    // Remove the least recently used files until the size of the store is at most
+    // `max_size`, the names of the removed files are returned.
+    fn evict(&mut self, max_size: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.size > max_size {
+            let lru = self
+                .files
+                .iter()
+                .min_by_key(|(_, file)| file.last_used)
+                .map(|(name, _)| name.clone());
+            let name = match lru {
+                Some(name) => name,
+                None => break,
+            };
+            if let Some(file) = self.files.remove(&name) {
+                self.size -= file.size;
+            }
+            evicted.push(name);
+        }
+        evicted
+    }
+}
+
+/// Write a body into a temporary file of the store. The file is removed if the writer is
+/// dropped before `commit`.
+pub struct AssetWriter {
+    store: Arc<AssetStore>,
+    name: String,
+    temp_path: PathBuf,
+    file: tokio::fs::File,
+    size: u64,
+    //the size in the name of the body.
+    expected_size: u64,
+    committed: bool,
+}
+
+impl AssetWriter {
+    pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.size += chunk.len() as u64;
+        if self.size > self.expected_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("body is larger than {} bytes", self.expected_size),
+            ));
+        }
+        self.file.write_all(chunk).await
+    }
+
+    /// Add the written body to the store, its hash must be checked first.
+    pub async fn commit(mut self) -> io::Result<()> {
+        if self.size != self.expected_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "body has {} bytes instead of {}",
+                    self.size, self.expected_size
+                ),
+            ));
+        }
+        self.file.sync_all().await?;
+        tokio::fs::rename(&self.temp_path, self.store.dir.join(&self.name)).await?;
+        self.committed = true;
+        let evicted = {
+            let mut entries = self.store.entries.lock().unwrap();
+            entries.insert(self.name.clone(), self.size);
+            entries.evict(self.store.max_size)
+        };
+        for name in evicted {
+            tokio::fs::remove_file(self.store.dir.join(name)).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AssetWriter {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+/// Send a stored body opened from the path returned by `get`, only the bytes of the range
+/// with a window.
+pub async fn send_file(
+    mut file: tokio::fs::File,
+    mut window: Option<RangeWindow>,
+    sender: &mut Sender,
+) -> io::Result<()> {
+    let mut buffer = vec![0; READ_CHUNK_SIZE];
+    while !window.as_ref().map_or(false, RangeWindow::is_done) {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        let data = match window.as_mut() {
+            Some(window) => window.trim(&buffer[..read]),
+            None => Bytes::copy_from_slice(&buffer[..read]),
+        };
+        if !data.is_empty() {
+            sender
+                .send_data(data)
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))?;
+        }
+    }
+    if !window.as_ref().map_or(true, RangeWindow::is_done) {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "stored body ended before the end of the range",
+        ));
+    }
+    Ok(())
+}
+
+// The name of a stored body: the hex sha256, the codings and the size of the body, ex:
+// `<sha>.gzip.1024`. `None` for a coding that can't be part of a file name.
+fn file_name(sha: &[u8], encoding: Option<&str>, size: u64) -> Option<String> {
+    let codings = codings_name(encoding)?;
+    let codings = if codings.is_empty() {
+        "identity".to_string()
+    } else {
+        codings
+    };
+    Some(format!("{}.{}.{}", hex::encode(sha), codings, size))
+}
+
+// A name built by `file_name`.
+fn is_file_name(name: &str) -> bool {
+    let mut parts = name.split('.');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(sha), Some(codings), Some(size), None) => {
+            sha.len() == 64
+                && sha.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+                && codings.split('+').all(|coding| {
+                    !coding.is_empty()
+                        && coding
+                            .chars()
+                            .all(|c| matches!(c, '0'..='9' | 'a'..='z' | '-'))
+                })
+                && is_number(size)
+        }
+        _ => false,
+    }
+}
+
+// The name of the temporary file of a writer: the name of the body, the count of the
+// writer and the temporary extension, ex: `<sha>.gzip.1024.3.tmp`.
+fn temp_name(name: &str, count: u64) -> String {
+    format!("{}.{}.{}", name, count, TEMP_EXTENSION)
+}
+
+// A name built by `temp_name`.
+fn is_temp_name(name: &str) -> bool {
+    name.strip_suffix(TEMP_EXTENSION)
+        .and_then(|name| name.strip_suffix('.'))
+        .and_then(|name| name.rsplit_once('.'))
+        .map_or(false, |(name, count)| {
+            is_number(count) && is_file_name(name)
+        })
+}
+
+fn is_number(value: &str) -> bool {
+    !value.is_empty() && value.chars().all(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn store_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("icx-proxy-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    async fn store(store: &Arc<AssetStore>, sha: &[u8], body: &[u8]) {
+        let mut writer = store
+            .writer(sha, Some("gzip"), body.len() as u64)
+            .await
+            .unwrap();
+        writer.write(body).await.unwrap();
+        writer.commit().await.unwrap();
+    }
+
+    #[test]
+    fn test_file_name() {
+        let sha = [0xab; 32];
+        assert_eq!(
+            Some(format!("{}.identity.10", "ab".repeat(32))),
+            file_name(&sha, None, 10)
+        );
+        assert_eq!(
+            Some(format!("{}.gzip+br.10", "ab".repeat(32))),
+            file_name(&sha, Some("GZIP, identity, br"), 10)
+        );
+        assert_eq!(None, file_name(&sha, Some("../gzip"), 10));
+    }
+
+    #[test]
+    fn test_asset_store_open() {
+        let dir = store_dir("asset-store-open");
+        std::fs::create_dir_all(&dir).unwrap();
+        let name = file_name(&[1; 32], Some("gzip"), 40).unwrap();
+        assert!(is_file_name(&name));
+        assert!(is_temp_name(&temp_name(&name, 3)));
+        std::fs::write(dir.join(&name), [1; 40]).unwrap();
+        std::fs::write(dir.join(temp_name(&name, 3)), [1; 20]).unwrap();
+        //the other files of the directory are not part of the store.
+        let others = [
+            "notes.txt",
+            "backup.tmp",
+            "abcd.gzip.40",
+            &format!("{}.identity.40", "AB".repeat(32)),
+            &format!("{}.gzip.40.tmp", "ab".repeat(32)),
+        ];
+        for other in others.iter() {
+            assert!(!is_file_name(other) && !is_temp_name(other));
+            std::fs::write(dir.join(other), [0; 100]).unwrap();
+        }
+
+        let asset_store = AssetStore::open(&dir, 50).unwrap();
+        assert!(asset_store.get(&[1; 32], Some("gzip"), 40).is_some());
+        assert!(!dir.join(temp_name(&name, 3)).exists());
+        for other in others.iter() {
+            assert!(dir.join(other).exists());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_asset_store() {
+        let dir = store_dir("asset-store");
+        let asset_store = Arc::new(AssetStore::open(&dir, 100).unwrap());
+        assert!(asset_store.get(&[1; 32], Some("gzip"), 40).is_none());
+
+        store(&asset_store, &[1; 32], &[1; 40]).await;
+        let path = asset_store.get(&[1; 32], Some("gzip"), 40).unwrap();
+        assert_eq!(vec![1; 40], std::fs::read(&path).unwrap());
+        //the encoding and the size are part of the key.
+        assert!(asset_store.get(&[1; 32], None, 40).is_none());
+        assert!(asset_store.get(&[1; 32], Some("gzip"), 41).is_none());
+
+        //a body not committed is not stored.
+        let mut writer = asset_store
+            .writer(&[2; 32], Some("gzip"), 40)
+            .await
+            .unwrap();
+        writer.write(&[2; 40]).await.unwrap();
+        drop(writer);
+        assert!(asset_store.get(&[2; 32], Some("gzip"), 40).is_none());
+        assert_eq!(1, std::fs::read_dir(&dir).unwrap().count());
+
+        //the least recently used body is removed.
+        store(&asset_store, &[3; 32], &[3; 40]).await;
+        asset_store.get(&[1; 32], Some("gzip"), 40).unwrap();
+        store(&asset_store, &[4; 32], &[4; 40]).await;
+        assert!(asset_store.get(&[1; 32], Some("gzip"), 40).is_some());
+        assert!(asset_store.get(&[3; 32], Some("gzip"), 40).is_none());
+        assert!(asset_store.get(&[4; 32], Some("gzip"), 40).is_some());
+
+        //a body larger than the store, or with another size, is not stored.
+        assert!(asset_store
+            .writer(&[5; 32], Some("gzip"), 101)
+            .await
+            .is_err());
+        let mut writer = asset_store
+            .writer(&[5; 32], Some("gzip"), 40)
+            .await
+            .unwrap();
+        writer.write(&[5; 30]).await.unwrap();
+        assert!(writer.commit().await.is_err());
+        assert!(asset_store.get(&[5; 32], Some("gzip"), 40).is_none());
+
+        //the stored bodies are kept when the store is opened again.
+        let asset_store = AssetStore::open(&dir, 100).unwrap();
+        assert!(asset_store.get(&[1; 32], Some("gzip"), 40).is_some());
+        assert!(asset_store.get(&[4; 32], Some("gzip"), 40).is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,305 @@
+use flate2::write::{DeflateDecoder, GzDecoder};
+use sha2::{Digest, Sha256};
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+// Buffer size of the brotli decoder.
+const BROTLI_BUFFER_SIZE: usize = 64 * 1024;
+// Small bodies can have a large compression ratio, the ratio is only checked
+// once this many bytes are decoded.
+const MIN_DECODED_SIZE_FOR_RATIO: u64 = 1024 * 1024;
+
+/// Limits of the decoding of a compressed body, `None` is no limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DecompressionLimits {
+    /// Maximum size of the decoded body.
+    pub max_size: Option<u64>,
+    /// Maximum ratio between the decoded and the encoded size, larger ratios are
+    /// decompression bombs.
+    pub max_ratio: Option<u64>,
+}
+
+/// Incremental sha256 of a body received in chunks, hashed after decoding its
+/// `Content-Encoding` like the certified hash of the v1 certification.
+pub struct BodyHasher {
+    decoder: Box<dyn Decode>,
+    encoded_length: Arc<AtomicU64>,
+}
+
+// A decoder writing the decoded bytes into the next decoder, down to the hasher.
+trait Decode: Write + Send {
+    fn finish(self: Box<Self>) -> io::Result<HashWriter>;
+}
+
+// Hashes the decoded bytes, within the limits.
+struct HashWriter {
+    sha256: Sha256,
+    length: u64,
+    encoded_length: Arc<AtomicU64>,
+    limits: DecompressionLimits,
+}
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.length += buf.len() as u64;
+        if let Some(max_size) = self.limits.max_size {
+            if self.length > max_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("decoded body is larger than {} bytes", max_size),
+                ));
+            }
+        }
+        if let Some(max_ratio) = self.limits.max_ratio {
+            let encoded_length = self.encoded_length.load(Ordering::Relaxed);
+            if self.length > MIN_DECODED_SIZE_FOR_RATIO
+                && self.length > encoded_length.saturating_mul(max_ratio)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "decoded body is more than {} times larger than the encoded body",
+                        max_ratio
+                    ),
+                ));
+            }
+        }
+        self.sha256.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Decode for HashWriter {
+    fn finish(self: Box<Self>) -> io::Result<HashWriter> {
+        Ok(*self)
+    }
+}
+
+impl Decode for GzDecoder<Box<dyn Decode>> {
+    fn finish(self: Box<Self>) -> io::Result<HashWriter> {
+        GzDecoder::finish(*self)?.finish()
+    }
+}
+
+impl Decode for DeflateDecoder<Box<dyn Decode>> {
+    fn finish(self: Box<Self>) -> io::Result<HashWriter> {
+        DeflateDecoder::finish(*self)?.finish()
+    }
+}
+
+impl Decode for brotli::DecompressorWriter<Box<dyn Decode>> {
+    fn finish(self: Box<Self>) -> io::Result<HashWriter> {
+        self.into_inner()
+            .map_err(|_| io::Error::new(io::ErrorKind::UnexpectedEof, "incomplete brotli stream"))?
+            .finish()
+    }
+}
+
+impl Decode for zstd::stream::write::Decoder<'static, Box<dyn Decode>> {
+    fn finish(mut self: Box<Self>) -> io::Result<HashWriter> {
+        self.flush()?;
+        self.into_inner().finish()
+    }
+}
+
+impl BodyHasher {
+    /// A hasher of the body decoded with the `Content-Encoding` header value. The codings
+    /// are listed in the order they were applied, ex: `gzip, br`, and are decoded in the
+    /// reverse order. The limits only apply to an encoded body.
+    pub fn new(encoding: Option<&str>, limits: DecompressionLimits) -> Result<Self, String> {
+        let codings = content_codings(encoding);
+        let encoded_length = Arc::new(AtomicU64::new(0));
+        let mut decoder: Box<dyn Decode> = Box::new(HashWriter {
+            sha256: Sha256::new(),
+            length: 0,
+            encoded_length: encoded_length.clone(),
+            limits: if codings.is_empty() {
+                DecompressionLimits::default()
+            } else {
+                limits
+            },
+        });
+        // The first applied coding is the last decoded, its decoder writes into the hasher.
+        for coding in codings {
+            decoder = match coding.as_str() {
+                "gzip" | "x-gzip" => Box::new(GzDecoder::new(decoder)),
+                "deflate" => Box::new(DeflateDecoder::new(decoder)),
+                "br" => Box::new(brotli::DecompressorWriter::new(decoder, BROTLI_BUFFER_SIZE)),
+                "zstd" => Box::new(
+                    zstd::stream::write::Decoder::new(decoder)
+                        .map_err(|e| format!("Body could not be decoded: {}", e))?,
+                ),
+                _ => return Err(format!("Unsupported Content-Encoding '{}'", coding)),
+            };
+        }
+        Ok(BodyHasher {
+            decoder,
+            encoded_length,
+        })
+    }
+
+    /// Hash the next chunk of the body.
+    pub fn update(&mut self, chunk: &[u8]) -> Result<(), String> {
+        self.encoded_length
+            .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        self.decoder
+            .write_all(chunk)
+            .map_err(|e| format!("Body could not be decoded: {}", e))
+    }
+
+    /// The sha256 of the whole body, once all the chunks are hashed.
+    pub fn finish(self) -> Result<[u8; 32], String> {
+        let writer = self
+            .decoder
+            .finish()
+            .map_err(|e| format!("Body could not be decoded: {}", e))?;
+        Ok(writer.sha256.finalize().into())
+    }
+}
+
+/// The codings of a `Content-Encoding` header value in the order they were applied,
+/// lowercased and without `identity`.
+pub fn content_codings(encoding: Option<&str>) -> Vec<String> {
+    encoding
+        .unwrap_or_default()
+        .split(',')
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty() && coding != "identity")
+        .collect()
+}
+
+/// The codings of a `Content-Encoding` header value joined with `+`, ex: `gzip+br`, to name
+/// an encoded body. Empty without coding, `None` when a coding has other characters than
+/// letters, digits and `-`.
+pub fn codings_name(encoding: Option<&str>) -> Option<String> {
+    let codings = content_codings(encoding);
+    if codings.iter().any(|coding| {
+        !coding
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    }) {
+        return None;
+    }
+    Some(codings.join("+"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+
+    fn hash_chunks(encoding: Option<&str>, body: &[u8], chunk_size: usize) -> [u8; 32] {
+        let mut hasher = BodyHasher::new(encoding, DecompressionLimits::default()).unwrap();
+        for chunk in body.chunks(chunk_size) {
+            hasher.update(chunk).unwrap();
+        }
+        hasher.finish().unwrap()
+    }
+
+    fn gzip(body: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn brotli(body: &[u8]) -> Vec<u8> {
+        let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+        encoder.write_all(body).unwrap();
+        encoder.into_inner()
+    }
+
+    #[test]
+    fn test_body_hasher() {
+        let body = b"hello world, hello world, hello world".repeat(100);
+        let sha: [u8; 32] = Sha256::digest(&body).into();
+        assert_eq!(sha, hash_chunks(None, &body, 7));
+        assert_eq!(sha, hash_chunks(Some("identity"), &body, body.len()));
+
+        let gzipped = gzip(&body);
+        assert_eq!(sha, hash_chunks(Some("gzip"), &gzipped, 3));
+        assert_eq!(sha, hash_chunks(Some("gzip"), &gzipped, gzipped.len()));
+
+        //a truncated body can't be decoded.
+        let mut hasher = BodyHasher::new(Some("gzip"), DecompressionLimits::default()).unwrap();
+        hasher.update(&gzipped[..gzipped.len() / 2]).unwrap();
+        assert!(hasher.finish().is_err());
+    }
+
+    #[test]
+    fn test_body_hasher_encodings() {
+        let body = b"hello world, hello world, hello world".repeat(100);
+        let sha: [u8; 32] = Sha256::digest(&body).into();
+
+        assert_eq!(sha, hash_chunks(Some("br"), &brotli(&body), 5));
+        let zstd = zstd::stream::encode_all(&body[..], 3).unwrap();
+        assert_eq!(sha, hash_chunks(Some("zstd"), &zstd, 5));
+        //gzip applied first, then brotli.
+        assert_eq!(sha, hash_chunks(Some("gzip, br"), &brotli(&gzip(&body)), 5));
+        assert_eq!(sha, hash_chunks(Some("GZIP,identity"), &gzip(&body), 5));
+
+        assert_eq!(
+            Some("Unsupported Content-Encoding 'compress'".to_string()),
+            BodyHasher::new(Some("gzip, compress"), DecompressionLimits::default()).err()
+        );
+    }
+
+    fn hash_limited(
+        encoding: Option<&str>,
+        body: &[u8],
+        limits: DecompressionLimits,
+    ) -> Result<[u8; 32], String> {
+        let mut hasher = BodyHasher::new(encoding, limits).unwrap();
+        for chunk in body.chunks(64 * 1024) {
+            hasher.update(chunk)?;
+        }
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_body_hasher_limits() {
+        let max_size = DecompressionLimits {
+            max_size: Some(1_000),
+            max_ratio: None,
+        };
+        let body = vec![0u8; 10_000];
+        assert!(hash_limited(Some("gzip"), &gzip(&body), max_size).is_err());
+        //the limits only apply to an encoded body.
+        assert!(hash_limited(None, &body, max_size).is_ok());
+
+        //10mb of zeros is compressed ~1000 times.
+        let max_ratio = DecompressionLimits {
+            max_size: None,
+            max_ratio: Some(100),
+        };
+        let bomb = gzip(&vec![0u8; 10 * 1024 * 1024]);
+        assert!(hash_limited(Some("gzip"), &bomb, max_ratio).is_err());
+        assert!(hash_limited(Some("gzip"), &bomb, DecompressionLimits::default()).is_ok());
+        //small bodies are not checked against the ratio.
+        assert!(hash_limited(Some("gzip"), &gzip(&body), max_ratio).is_ok());
+        let json = (0..500_000)
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        assert!(hash_limited(Some("gzip"), &gzip(json.as_bytes()), max_ratio).is_ok());
+    }
+
+    #[test]
+    fn test_codings_name() {
+        assert_eq!(Some(String::new()), codings_name(None));
+        assert_eq!(Some(String::new()), codings_name(Some("identity")));
+        assert_eq!(
+            Some("gzip+br".to_string()),
+            codings_name(Some("GZIP, identity, br"))
+        );
+        assert_eq!(None, codings_name(Some("../gzip")));
+    }
+}
@@ -1,12 +1,15 @@
+use crate::config::DnsAliasConfig;
 use async_trait::async_trait;
 use candid::{Decode, Encode};
 use core::convert::From;
-use ic_agent::agent::http_transport::ReqwestHttpReplicaV2Transport;
 use ic_agent::ic_types::Principal;
 use ic_agent::Agent;
 use redis::aio::MultiplexedConnection;
 use redis::AsyncCommands;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::{mpsc, Mutex};
 
 #[derive(Clone, Debug)]
@@ -17,34 +20,22 @@ pub struct PhoneBookCanisterParam {
 impl PhoneBookCanisterParam {
     pub fn new(
         phonebook_id: &str,
-        network_url: &str,
+        agent: Agent,
         logger: &slog::Logger,
     ) -> Result<PhoneBookCanisterParam, String> {
         Principal::from_text(phonebook_id)
-            .or_else(|err| {
+            .map(|principal| PhoneBookCanisterParam {
+                canister_id: principal,
+                agent,
+            })
+            .map_err(|err| {
                 slog::error!(
                     logger,
                     "Error Phone book canister id not a principal: {}, id:{}",
                     err,
                     phonebook_id,
                 );
-                Err("Error Phone book canister id not a principal".to_string())
-            })
-            .and_then(|principal| {
-                ReqwestHttpReplicaV2Transport::create(network_url)
-                    .and_then(|transport| Agent::builder().with_transport(transport).build())
-                    .map(|agent| PhoneBookCanisterParam {
-                        canister_id: principal,
-                        agent,
-                    })
-                    .map_err(|err| {
-                        slog::error!(
-                            logger,
-                            "Error Phone book canister connection error: {}",
-                            err
-                        );
-                        "Error Phone book agent connection error".to_string()
-                    })
+                "Error Phone book canister id not a principal".to_string()
             })
     }
 }
@@ -95,6 +86,16 @@ impl RedisParam {
             None
         }
     }
+
+    /// Get a binary value, `None` when the key doesn't exist.
+    pub async fn get_bytes(&self, key: &str) -> redis::RedisResult<Option<Vec<u8>>> {
+        self.connection.lock().await.get(key).await
+    }
+
+    /// Set a binary value with a time to live in seconds.
+    pub async fn set_bytes(&self, key: &str, value: &[u8], seconds: usize) -> redis::RedisResult<()> {
+        self.connection.lock().await.set_ex(key, value, seconds).await
+    }
 }
 
 pub async fn resolve_canister_id_from_uri(
@@ -103,7 +104,7 @@ pub async fn resolve_canister_id_from_uri(
     phonebook_param: Option<&PhoneBookCanisterParam>,
     canister_id_resolver: impl ResolveCanisterId,
     logger: &slog::Logger,
-) -> Option<(Principal, String)> {
+) -> Option<(Vec<Principal>, String)> {
     //    let (_, canister_id) = url::form_urlencoded::parse(url.query()?.as_bytes())
     //        .find(|(name, _)| name == "canisterId")?;
     //    Principal::from_text(canister_id.as_ref()).ok()
@@ -113,12 +114,13 @@ pub async fn resolve_canister_id_from_uri(
         let x = segment.next()?;
         //detect if it's a canister id
         let id = match Principal::from_text(x) {
-            Ok(id) => id,
+            Ok(id) => vec![id],
             //not a caniter if, try to see if it's an alias.
             Err(_) => {
                 canister_id_resolver
                     .resolve_canister_id_from_name(x, redis_param, phonebook_param, &logger)
                     .await?
+                    .canister_ids()?
             }
         };
 
@@ -142,6 +144,80 @@ pub async fn resolve_canister_id_from_uri(
     None
 }
 
+/// Resolve the canister from the `Host` header when it is a custom domain known by the
+/// resolver or a subdomain of one of the configured base domains: `<canister-id>.<domain>`,
+/// `<alias>.<domain>` or `<canister-id>.raw.<domain>`. The request path and query are sent
+/// to the canister untouched.
+pub async fn resolve_canister_id_from_host(
+    host: &str,
+    url: &hyper::Uri,
+    domains: &[String],
+    redis_param: Option<&RedisParam>,
+    phonebook_param: Option<&PhoneBookCanisterParam>,
+    canister_id_resolver: impl ResolveCanisterId,
+    logger: &slog::Logger,
+) -> Option<(Vec<Principal>, String)> {
+    let id = match canister_id_resolver
+        .resolve_canister_id_from_domain(strip_port(host), logger)
+        .await
+    {
+        Some(id) => vec![id],
+        None => {
+            let (name, _) = split_host(host, domains)?;
+            match Principal::from_text(name) {
+                Ok(id) => vec![id],
+                Err(_) => {
+                    canister_id_resolver
+                        .resolve_canister_id_from_name(name, redis_param, phonebook_param, logger)
+                        .await?
+                        .canister_ids()?
+                }
+            }
+        }
+    };
+    let uri = url
+        .path_and_query()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "/".to_string());
+    Some((id, uri))
+}
+
+/// Return true if the host is a `<name>.raw.<domain>` subdomain of a configured base domain.
+pub fn is_raw_host(host: &str, domains: &[String]) -> bool {
+    split_host(host, domains)
+        .map(|(_, raw)| raw)
+        .unwrap_or(false)
+}
+
+//split the host in the subdomain label and a flag set if the raw subdomain is used.
+fn split_host<'a>(host: &'a str, domains: &[String]) -> Option<(&'a str, bool)> {
+    let host = strip_port(host);
+    domains.iter().find_map(|domain| {
+        if host.len() <= domain.len() + 1 {
+            return None;
+        }
+        let (subdomain, base) = host.split_at(host.len() - domain.len());
+        if !base.eq_ignore_ascii_case(domain) {
+            return None;
+        }
+        let subdomain = subdomain.strip_suffix('.')?;
+        let (name, raw) = match subdomain.rsplit_once('.') {
+            Some((name, raw)) if raw.eq_ignore_ascii_case("raw") => (name, true),
+            _ => (subdomain, false),
+        };
+        (!name.is_empty() && !name.contains('.')).then(|| (name, raw))
+    })
+}
+
+//remove the port and the trailing dot of the host if any.
+fn strip_port(host: &str) -> &str {
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => host,
+    };
+    host.trim_end_matches('.')
+}
+
 fn path_segments(url: &hyper::Uri) -> Option<std::str::Split<'_, char>> {
     let path = url.path();
     if path.starts_with('/') {
@@ -151,17 +227,251 @@ fn path_segments(url: &hyper::Uri) -> Option<std::str::Split<'_, char>> {
     }
 }
 
+/// Value stored in Redis for an alias unknown by the phone book.
+pub const NEGATIVE_ALIAS_VALUE: &str = "";
+//separator of the canister ids of an alias stored in Redis.
+const REDIS_CANISTER_SEPARATOR: char = ',';
+
+/// Result of an alias resolution. `Found` contains all the canisters of the alias and
+/// is never empty. `NotFound` is a confirmed miss and can be cached,
+/// a failed resolution is returned as `None` by the resolvers.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AliasLookup {
+    Found(Vec<Principal>),
+    NotFound,
+}
+
+impl AliasLookup {
+    pub fn canister_ids(self) -> Option<Vec<Principal>> {
+        match self {
+            AliasLookup::Found(canister_ids) => Some(canister_ids),
+            AliasLookup::NotFound => None,
+        }
+    }
+
+    fn from_list(canister_ids: Vec<Principal>) -> Self {
+        if canister_ids.is_empty() {
+            AliasLookup::NotFound
+        } else {
+            AliasLookup::Found(canister_ids)
+        }
+    }
+}
+
+static NEXT_CANISTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Order the canisters of an alias for one request. The first canister is chosen
+/// round-robin to spread the load, the next ones are used in order for failover.
+pub fn failover_order(canister_ids: Vec<Principal>) -> Vec<Principal> {
+    let start = NEXT_CANISTER.fetch_add(1, Ordering::Relaxed);
+    rotate(canister_ids, start)
+}
+
+fn rotate(mut canister_ids: Vec<Principal>, start: usize) -> Vec<Principal> {
+    if !canister_ids.is_empty() {
+        let len = canister_ids.len();
+        canister_ids.rotate_left(start % len);
+    }
+    canister_ids
+}
+
+#[async_trait]
+pub trait ResolveCanisterId: Send + Sync {
+    async fn resolve_canister_id_from_name(
+        &self,
+        name: &str,
+        redis_param: Option<&RedisParam>,
+        phonebook_param: Option<&PhoneBookCanisterParam>,
+        logger: &slog::Logger,
+    ) -> Option<AliasLookup>;
+
+    /// Resolve a full custom domain name. No domain is known by default.
+    async fn resolve_canister_id_from_domain(
+        &self,
+        _domain: &str,
+        _logger: &slog::Logger,
+    ) -> Option<Principal> {
+        None
+    }
+}
+
+/// Resolve names with the static `--dns-alias` map before calling the wrapped resolver,
+/// so locally mapped domains and aliases never need a Redis or phone book round-trip.
+#[derive(Clone)]
+pub struct StaticAccess<R> {
+    config: Arc<DnsAliasConfig>,
+    inner: R,
+}
+
+impl<R> StaticAccess<R> {
+    pub fn new(config: Arc<DnsAliasConfig>, inner: R) -> Self {
+        StaticAccess { config, inner }
+    }
+}
+
+#[async_trait]
+impl<R: ResolveCanisterId> ResolveCanisterId for StaticAccess<R> {
+    async fn resolve_canister_id_from_name(
+        &self,
+        name: &str,
+        redis_param: Option<&RedisParam>,
+        phonebook_param: Option<&PhoneBookCanisterParam>,
+        logger: &slog::Logger,
+    ) -> Option<AliasLookup> {
+        if let Some(canister_id) = self.config.resolve_alias(name) {
+            slog::debug!(logger, "Alias {} resolved from static config", name);
+            return Some(AliasLookup::Found(vec![canister_id]));
+        }
+        self.inner
+            .resolve_canister_id_from_name(name, redis_param, phonebook_param, logger)
+            .await
+    }
+
+    async fn resolve_canister_id_from_domain(
+        &self,
+        domain: &str,
+        logger: &slog::Logger,
+    ) -> Option<Principal> {
+        if let Some(canister_id) = self.config.resolve_domain(domain) {
+            slog::debug!(logger, "Domain {} resolved from static config", domain);
+            return Some(canister_id);
+        }
+        self.inner
+            .resolve_canister_id_from_domain(domain, logger)
+            .await
+    }
+}
+
+/// In-process alias cache with a time to live and a LRU eviction when it's full.
+/// Unknown aliases are kept with their own, usually shorter, time to live.
+pub struct AliasCache {
+    entries: std::sync::Mutex<AliasCacheEntries>,
+    capacity: usize,
+    ttl: Duration,
+    negative_ttl: Duration,
+}
+
+#[derive(Default)]
+struct AliasCacheEntries {
+    map: HashMap<String, AliasCacheEntry>,
+    //incremented at each access, used to find the least recently used entry.
+    tick: u64,
+}
+
+struct AliasCacheEntry {
+    lookup: AliasLookup,
+    expire_at: Instant,
+    last_used: u64,
+}
+
+impl AliasCache {
+    /// A capacity of 0 disables the cache.
+    pub fn new(capacity: usize, ttl: Duration, negative_ttl: Duration) -> Self {
+        AliasCache {
+            entries: std::sync::Mutex::new(AliasCacheEntries::default()),
+            capacity,
+            ttl,
+            negative_ttl,
+        }
+    }
+
+    pub fn get(&self, alias: &str) -> Option<AliasLookup> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.tick += 1;
+        let tick = entries.tick;
+        match entries.map.get_mut(alias) {
+            Some(entry) if entry.expire_at > Instant::now() => {
+                entry.last_used = tick;
+                Some(entry.lookup.clone())
+            }
+            Some(_) => {
+                entries.map.remove(alias);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, alias: &str, lookup: AliasLookup) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.map.len() >= self.capacity && !entries.map.contains_key(alias) {
+            let now = Instant::now();
+            entries.map.retain(|_, entry| entry.expire_at > now);
+            if entries.map.len() >= self.capacity {
+                let lru = entries
+                    .map
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(alias, _)| alias.clone());
+                if let Some(lru) = lru {
+                    entries.map.remove(&lru);
+                }
+            }
+        }
+        entries.tick += 1;
+        let ttl = match lookup {
+            AliasLookup::Found(_) => self.ttl,
+            AliasLookup::NotFound => self.negative_ttl,
+        };
+        let entry = AliasCacheEntry {
+            lookup,
+            expire_at: Instant::now() + ttl,
+            last_used: entries.tick,
+        };
+        entries.map.insert(alias.to_string(), entry);
+    }
+}
+
+/// Resolve aliases with the in-process cache before calling the wrapped resolver
+/// and keep the resolved canister id in the cache.
+#[derive(Clone)]
+pub struct CachedAccess<R> {
+    cache: Arc<AliasCache>,
+    inner: R,
+}
+
+impl<R> CachedAccess<R> {
+    pub fn new(cache: Arc<AliasCache>, inner: R) -> Self {
+        CachedAccess { cache, inner }
+    }
+}
+
 #[async_trait]
-pub trait ResolveCanisterId {
+impl<R: ResolveCanisterId> ResolveCanisterId for CachedAccess<R> {
     async fn resolve_canister_id_from_name(
         &self,
         name: &str,
         redis_param: Option<&RedisParam>,
         phonebook_param: Option<&PhoneBookCanisterParam>,
         logger: &slog::Logger,
-    ) -> Option<Principal>;
+    ) -> Option<AliasLookup> {
+        if let Some(lookup) = self.cache.get(name) {
+            slog::debug!(logger, "Alias {} resolved from memory cache: {:?}", name, lookup);
+            return Some(lookup);
+        }
+        let lookup = self
+            .inner
+            .resolve_canister_id_from_name(name, redis_param, phonebook_param, logger)
+            .await?;
+        self.cache.insert(name, lookup.clone());
+        Some(lookup)
+    }
+
+    async fn resolve_canister_id_from_domain(
+        &self,
+        domain: &str,
+        logger: &slog::Logger,
+    ) -> Option<Principal> {
+        self.inner
+            .resolve_canister_id_from_domain(domain, logger)
+            .await
+    }
 }
 
+#[derive(Clone, Copy)]
 pub struct RealAccess;
 
 #[async_trait]
@@ -172,86 +482,91 @@ impl ResolveCanisterId for RealAccess {
         redis_param: Option<&RedisParam>,
         phonebook_param: Option<&PhoneBookCanisterParam>,
         logger: &slog::Logger,
-    ) -> Option<Principal> {
+    ) -> Option<AliasLookup> {
         //get canister id from redis cache.
-        let found_principal = if let Some(RedisParam { connection, .. }) = redis_param.as_ref() {
+        let cached = if let Some(RedisParam { connection, .. }) = redis_param.as_ref() {
             let mut redis_connection = connection.as_ref().lock().await;
 
             redis_connection
                 .get::<_, String>(&name)
                 .await
                 .and_then(|s| {
-                    Principal::from_text(s).map_err(|_| {
-                        redis::RedisError::from((
-                            redis::ErrorKind::TypeError,
-                            "Redis canister id not a principal.",
-                        ))
-                    })
+                    if s == NEGATIVE_ALIAS_VALUE {
+                        return Ok(AliasLookup::NotFound);
+                    }
+                    s.split(REDIS_CANISTER_SEPARATOR)
+                        .map(Principal::from_text)
+                        .collect::<Result<Vec<_>, _>>()
+                        .map(AliasLookup::from_list)
+                        .map_err(|_| {
+                            redis::RedisError::from((
+                                redis::ErrorKind::TypeError,
+                                "Redis canister id not a principal.",
+                            ))
+                        })
                 })
                 .ok()
         } else {
             None
         };
+        if cached.is_some() {
+            return cached;
+        }
 
-        //don't use closure because of async call not compatible with.
         //call phone book canister if not found.
-        if let None = found_principal {
-            if let Some(phone_book) = phonebook_param {
-                //let canister_id = "r5m5i-tiaaa-aaaaj-acgaq-cai".to_string();
-                let response = phone_book
-                    .agent
-                    .query(&phone_book.canister_id, "lookup")
-                    .with_arg(&Encode!(&name).ok()?)
-                    .call()
-                    .await
-                    .map_err(|err| {
-                        slog::error!(
-                            logger,
-                            "Error Phone Book canister query call failed: {}",
-                            err
-                        );
-                    })
-                    .ok()?;
-                let canister_list = Decode!(response.as_slice(), Option<Vec<Principal>>)
-                    .map_err(|err| {
-                        slog::error!(
-                            logger,
-                            "Error during Phone Book canister reponse decoding: {}",
-                            err
-                        );
-                    })
-                    .ok()?;
-
-                slog::info!(
+        let phone_book = phonebook_param?;
+        let response = phone_book
+            .agent
+            .query(&phone_book.canister_id, "lookup")
+            .with_arg(&Encode!(&name).ok()?)
+            .call()
+            .await
+            .map_err(|err| {
+                slog::error!(
+                    logger,
+                    "Error Phone Book canister query call failed: {}",
+                    err
+                );
+            })
+            .ok()?;
+        let canister_list = Decode!(response.as_slice(), Option<Vec<Principal>>)
+            .map_err(|err| {
+                slog::error!(
                     logger,
-                    "Get canister id from phone book response: {:?}",
-                    canister_list
+                    "Error during Phone Book canister reponse decoding: {}",
+                    err
                 );
+            })
+            .ok()?;
 
-                let found_principal = canister_list.and_then(|canister_list| {
-                    (canister_list.len() > 0)
-                        .then(|| redis_param.as_ref())
-                        .and_then(|param| param)
-                        .map_or_else(||{
-                            Some(canister_list[0])
-                        },|RedisParam { redis_cache_tx, .. }| {
-                            redis_cache_tx
-                                .try_send((name.to_string(), canister_list[0].to_string()))
-                                .map_err(|err| {
-                                    slog::error!(
-                                        logger,
-                                        "Error could not send canister_id to the Redis channel: {}",
-                                        err
-                                    );
-                                })
-                                .ok();
-                            Some(canister_list[0])
-                        })
-                });
-                return found_principal;
-            }
+        slog::info!(
+            logger,
+            "Get canister id from phone book response: {:?}",
+            canister_list
+        );
+
+        //all the canisters are cached, an unknown alias is cached too with an empty list
+        //so it's not looked up again at each request.
+        let canister_list = canister_list.unwrap_or_default();
+        let value = canister_list
+            .iter()
+            .map(|canister_id| canister_id.to_string())
+            .collect::<Vec<_>>()
+            .join(&REDIS_CANISTER_SEPARATOR.to_string());
+        let lookup = AliasLookup::from_list(canister_list);
+        if let Some(RedisParam { redis_cache_tx, .. }) = redis_param {
+            redis_cache_tx
+                .try_send((name.to_string(), value))
+                .map_err(|err| {
+                    slog::error!(
+                        logger,
+                        "Error could not send canister_id to the Redis channel: {}",
+                        err
+                    );
+                })
+                .ok();
         }
-        found_principal
+        Some(lookup)
     }
 }
 
@@ -272,11 +587,323 @@ mod test {
             _redis_param: Option<&RedisParam>,
             _phonebook_param: Option<&PhoneBookCanisterParam>,
             _logger: &slog::Logger,
-        ) -> Option<Principal> {
-            (&self.0 == name).then(|| Principal::from_text(&self.1).unwrap())
+        ) -> Option<AliasLookup> {
+            if self.0 == name {
+                Some(AliasLookup::Found(
+                    self.1
+                        .split(',')
+                        .map(|id| Principal::from_text(id).unwrap())
+                        .collect(),
+                ))
+            } else {
+                Some(AliasLookup::NotFound)
+            }
         }
     }
 
+    fn test_logger() -> slog::Logger {
+        let decorator = slog_term::TermDecorator::new().build();
+        let drain = slog_term::FullFormat::new(decorator).build().fuse();
+        let drain = slog_async::Async::new(drain).build().fuse();
+        slog::Logger::root(drain, slog::o!())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_canister_id_from_host() {
+        let logger = test_logger();
+        let canister_resolver = TestAccess(
+            "uefa_nfts4g".to_string(),
+            "r5m5i-tiaaa-aaaaj-acgaq-cai".to_string(),
+        );
+        let domains = vec!["nft.origyn.network".to_string(), "localhost".to_string()];
+
+        let uri = "/uefa_nfts4g_0/info?query=owner".parse::<Uri>().unwrap();
+        let res = resolve_canister_id_from_host(
+            "r5m5i-tiaaa-aaaaj-acgaq-cai.nft.origyn.network",
+            &uri,
+            &domains,
+            None,
+            None,
+            canister_resolver.clone(),
+            &logger,
+        )
+        .await;
+        let (canister_ids, uri) = res.unwrap();
+        assert_eq!("/uefa_nfts4g_0/info?query=owner", uri);
+        assert_eq!("r5m5i-tiaaa-aaaaj-acgaq-cai", canister_ids[0].to_string());
+
+        let uri = "/-/uefa_nfts4g_0".parse::<Uri>().unwrap();
+        let res = resolve_canister_id_from_host(
+            "uefa_nfts4g.localhost:3000",
+            &uri,
+            &domains,
+            None,
+            None,
+            canister_resolver.clone(),
+            &logger,
+        )
+        .await;
+        let (canister_ids, uri) = res.unwrap();
+        assert_eq!("/-/uefa_nfts4g_0", uri);
+        assert_eq!("r5m5i-tiaaa-aaaaj-acgaq-cai", canister_ids[0].to_string());
+
+        let root = "/".parse::<Uri>().unwrap();
+        let res = resolve_canister_id_from_host(
+            "r5m5i-tiaaa-aaaaj-acgaq-cai.raw.NFT.origyn.network",
+            &root,
+            &domains,
+            None,
+            None,
+            canister_resolver.clone(),
+            &logger,
+        )
+        .await;
+        let (canister_ids, uri) = res.unwrap();
+        assert_eq!("/", uri);
+        assert_eq!("r5m5i-tiaaa-aaaaj-acgaq-cai", canister_ids[0].to_string());
+
+        //the base domain alone is left to the path resolution.
+        let res = resolve_canister_id_from_host(
+            "nft.origyn.network",
+            &root,
+            &domains,
+            None,
+            None,
+            canister_resolver.clone(),
+            &logger,
+        )
+        .await;
+        assert!(res.is_none());
+        //unknown alias
+        let res = resolve_canister_id_from_host(
+            "uefa_nfts3g.nft.origyn.network",
+            &root,
+            &domains,
+            None,
+            None,
+            canister_resolver.clone(),
+            &logger,
+        )
+        .await;
+        assert!(res.is_none());
+        //nested subdomain
+        let res = resolve_canister_id_from_host(
+            "a.uefa_nfts4g.nft.origyn.network",
+            &root,
+            &domains,
+            None,
+            None,
+            canister_resolver.clone(),
+            &logger,
+        )
+        .await;
+        assert!(res.is_none());
+        //not a configured domain
+        let res = resolve_canister_id_from_host(
+            "uefa_nfts4g.ic0.app",
+            &root,
+            &domains,
+            None,
+            None,
+            canister_resolver.clone(),
+            &logger,
+        )
+        .await;
+        assert!(res.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_static_access() {
+        let logger = test_logger();
+        let config = DnsAliasConfig::new(
+            &[
+                "origyn_static:ngrpb-5qaaa-aaaaj-adz7a-cai".to_string(),
+                "nft.mybrand.com:ngrpb-5qaaa-aaaaj-adz7a-cai".to_string(),
+            ],
+            None,
+        )
+        .unwrap();
+        let canister_resolver = StaticAccess::new(
+            Arc::new(config),
+            TestAccess(
+                "uefa_nfts4g".to_string(),
+                "r5m5i-tiaaa-aaaaj-acgaq-cai".to_string(),
+            ),
+        );
+        let domains = vec!["nft.origyn.network".to_string()];
+
+        //static alias in the path scheme.
+        let uri = "/-/origyn_static/-/token_0".parse::<Uri>().unwrap();
+        let (canister_ids, uri) =
+            resolve_canister_id_from_uri(&uri, None, None, canister_resolver.clone(), &logger)
+                .await
+                .unwrap();
+        assert_eq!("/-/token_0", uri);
+        assert_eq!("ngrpb-5qaaa-aaaaj-adz7a-cai", canister_ids[0].to_string());
+
+        //fall back to the wrapped resolver.
+        let uri = "/-/uefa_nfts4g/-/token_0".parse::<Uri>().unwrap();
+        let (canister_ids, _) =
+            resolve_canister_id_from_uri(&uri, None, None, canister_resolver.clone(), &logger)
+                .await
+                .unwrap();
+        assert_eq!("r5m5i-tiaaa-aaaaj-acgaq-cai", canister_ids[0].to_string());
+
+        //static custom domain.
+        let uri = "/token_0".parse::<Uri>().unwrap();
+        let (canister_ids, uri) = resolve_canister_id_from_host(
+            "nft.mybrand.com:443",
+            &uri,
+            &domains,
+            None,
+            None,
+            canister_resolver.clone(),
+            &logger,
+        )
+        .await
+        .unwrap();
+        assert_eq!("/token_0", uri);
+        assert_eq!("ngrpb-5qaaa-aaaaj-adz7a-cai", canister_ids[0].to_string());
+
+        //static alias as subdomain.
+        let (canister_ids, _) = resolve_canister_id_from_host(
+            "origyn_static.nft.origyn.network",
+            &uri.parse::<Uri>().unwrap(),
+            &domains,
+            None,
+            None,
+            canister_resolver.clone(),
+            &logger,
+        )
+        .await
+        .unwrap();
+        assert_eq!("ngrpb-5qaaa-aaaaj-adz7a-cai", canister_ids[0].to_string());
+    }
+
+    #[test]
+    fn test_alias_cache() {
+        let canister_a = Principal::from_text("r5m5i-tiaaa-aaaaj-acgaq-cai").unwrap();
+        let canister_b = Principal::from_text("ngrpb-5qaaa-aaaaj-adz7a-cai").unwrap();
+        let ttl = Duration::from_secs(60);
+
+        let cache = AliasCache::new(2, ttl, ttl);
+        cache.insert("a", AliasLookup::Found(vec![canister_a]));
+        cache.insert("b", AliasLookup::Found(vec![canister_b]));
+        assert_eq!(Some(AliasLookup::Found(vec![canister_a])), cache.get("a"));
+        //b is the least recently used entry and is evicted.
+        cache.insert("c", AliasLookup::NotFound);
+        assert!(cache.get("b").is_none());
+        assert_eq!(Some(AliasLookup::Found(vec![canister_a])), cache.get("a"));
+        assert_eq!(Some(AliasLookup::NotFound), cache.get("c"));
+
+        //expired entries are not returned.
+        let cache = AliasCache::new(2, Duration::from_secs(0), Duration::from_secs(0));
+        cache.insert("a", AliasLookup::Found(vec![canister_a]));
+        assert!(cache.get("a").is_none());
+
+        //negative entries have their own time to live.
+        let cache = AliasCache::new(2, ttl, Duration::from_secs(0));
+        cache.insert("a", AliasLookup::Found(vec![canister_a]));
+        cache.insert("b", AliasLookup::NotFound);
+        assert_eq!(Some(AliasLookup::Found(vec![canister_a])), cache.get("a"));
+        assert!(cache.get("b").is_none());
+
+        //disabled cache.
+        let cache = AliasCache::new(0, ttl, ttl);
+        cache.insert("a", AliasLookup::Found(vec![canister_a]));
+        assert!(cache.get("a").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cached_access() {
+        let logger = test_logger();
+        let ttl = Duration::from_secs(60);
+        let cache = Arc::new(AliasCache::new(10, ttl, ttl));
+        let canister_resolver = CachedAccess::new(
+            cache.clone(),
+            TestAccess(
+                "uefa_nfts4g".to_string(),
+                "r5m5i-tiaaa-aaaaj-acgaq-cai".to_string(),
+            ),
+        );
+
+        assert!(cache.get("uefa_nfts4g").is_none());
+        let lookup = canister_resolver
+            .resolve_canister_id_from_name("uefa_nfts4g", None, None, &logger)
+            .await
+            .unwrap();
+        assert_eq!(
+            "r5m5i-tiaaa-aaaaj-acgaq-cai",
+            lookup.clone().canister_ids().unwrap()[0].to_string()
+        );
+        assert_eq!(Some(lookup), cache.get("uefa_nfts4g"));
+
+        //unknown aliases are cached as negative entries.
+        let lookup = canister_resolver
+            .resolve_canister_id_from_name("uefa_nfts3g", None, None, &logger)
+            .await;
+        assert_eq!(Some(AliasLookup::NotFound), lookup);
+        assert_eq!(Some(AliasLookup::NotFound), cache.get("uefa_nfts3g"));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_all_canisters_of_alias() {
+        let logger = test_logger();
+        let canister_resolver = TestAccess(
+            "uefa_nfts4g".to_string(),
+            "r5m5i-tiaaa-aaaaj-acgaq-cai,ngrpb-5qaaa-aaaaj-adz7a-cai".to_string(),
+        );
+        let uri = "/-/uefa_nfts4g/-/uefa_nfts4g_0".parse::<Uri>().unwrap();
+        let (canister_ids, uri) =
+            resolve_canister_id_from_uri(&uri, None, None, canister_resolver.clone(), &logger)
+                .await
+                .unwrap();
+        assert_eq!("/-/uefa_nfts4g_0", uri);
+        assert_eq!(
+            vec!["r5m5i-tiaaa-aaaaj-acgaq-cai", "ngrpb-5qaaa-aaaaj-adz7a-cai"],
+            canister_ids
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_rotate() {
+        let canister_a = Principal::from_text("r5m5i-tiaaa-aaaaj-acgaq-cai").unwrap();
+        let canister_b = Principal::from_text("ngrpb-5qaaa-aaaaj-adz7a-cai").unwrap();
+        let canister_c = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let canister_ids = vec![canister_a, canister_b, canister_c];
+
+        assert_eq!(canister_ids, rotate(canister_ids.clone(), 0));
+        assert_eq!(
+            vec![canister_b, canister_c, canister_a],
+            rotate(canister_ids.clone(), 1)
+        );
+        assert_eq!(
+            vec![canister_c, canister_a, canister_b],
+            rotate(canister_ids.clone(), 5)
+        );
+        assert!(rotate(vec![], 3).is_empty());
+        assert_eq!(3, failover_order(canister_ids).len());
+    }
+
+    #[test]
+    fn test_is_raw_host() {
+        let domains = vec!["nft.origyn.network".to_string()];
+        assert!(is_raw_host(
+            "r5m5i-tiaaa-aaaaj-acgaq-cai.raw.nft.origyn.network",
+            &domains
+        ));
+        assert!(is_raw_host("uefa_nfts4g.raw.nft.origyn.network:443", &domains));
+        assert!(!is_raw_host(
+            "r5m5i-tiaaa-aaaaj-acgaq-cai.nft.origyn.network",
+            &domains
+        ));
+        assert!(!is_raw_host("raw.nft.origyn.network", &domains));
+        assert!(!is_raw_host("nft.origyn.network", &domains));
+    }
+
     #[tokio::test]
     async fn test_resolve_canister_id_from_uri() {
         let decorator = slog_term::TermDecorator::new().build();
@@ -293,26 +920,26 @@ mod test {
         let res =
             resolve_canister_id_from_uri(&uri, None, None, canister_resolver.clone(), &logger)
                 .await;
-        let (canister_id, uri) = res.unwrap();
+        let (canister_ids, uri) = res.unwrap();
         assert_eq!("/-/uefa_nfts4g_0", uri);
-        assert_eq!("r5m5i-tiaaa-aaaaj-acgaq-cai", canister_id.to_string());
+        assert_eq!("r5m5i-tiaaa-aaaaj-acgaq-cai", canister_ids[0].to_string());
         let uri = "/-/r5m5i-tiaaa-aaaaj-acgaq-cai/-/uefa_nfts4g_0"
             .parse::<Uri>()
             .unwrap();
         let res =
             resolve_canister_id_from_uri(&uri, None, None, canister_resolver.clone(), &logger)
                 .await;
-        let (canister_id, uri) = res.unwrap();
+        let (canister_ids, uri) = res.unwrap();
         assert_eq!("/-/uefa_nfts4g_0", uri);
-        assert_eq!("r5m5i-tiaaa-aaaaj-acgaq-cai", canister_id.to_string());
+        assert_eq!("r5m5i-tiaaa-aaaaj-acgaq-cai", canister_ids[0].to_string());
 
         let uri = "/-/r5m5i-tiaaa-aaaaj-acgaq-cai/-/1".parse::<Uri>().unwrap();
         let res =
             resolve_canister_id_from_uri(&uri, None, None, canister_resolver.clone(), &logger)
                 .await;
-        let (canister_id, uri) = res.unwrap();
+        let (canister_ids, uri) = res.unwrap();
         assert_eq!("/-/1", uri);
-        assert_eq!("r5m5i-tiaaa-aaaaj-acgaq-cai", canister_id.to_string());
+        assert_eq!("r5m5i-tiaaa-aaaaj-acgaq-cai", canister_ids[0].to_string());
 
         let uri = "/-/r5m5i-tiaaa-aaaaj-acgaq-cai/-/1/ex"
             .parse::<Uri>()
@@ -320,9 +947,9 @@ mod test {
         let res =
             resolve_canister_id_from_uri(&uri, None, None, canister_resolver.clone(), &logger)
                 .await;
-        let (canister_id, uri) = res.unwrap();
+        let (canister_ids, uri) = res.unwrap();
         assert_eq!("/-/1/ex", uri);
-        assert_eq!("r5m5i-tiaaa-aaaaj-acgaq-cai", canister_id.to_string());
+        assert_eq!("r5m5i-tiaaa-aaaaj-acgaq-cai", canister_ids[0].to_string());
 
         let uri = "/-/r5m5i-tiaaa-aaaaj-acgaq-cai/-/1/ex/yx"
             .parse::<Uri>()
@@ -330,9 +957,9 @@ mod test {
         let res =
             resolve_canister_id_from_uri(&uri, None, None, canister_resolver.clone(), &logger)
                 .await;
-        let (canister_id, uri) = res.unwrap();
+        let (canister_ids, uri) = res.unwrap();
         assert_eq!("/-/1/ex/yx", uri);
-        assert_eq!("r5m5i-tiaaa-aaaaj-acgaq-cai", canister_id.to_string());
+        assert_eq!("r5m5i-tiaaa-aaaaj-acgaq-cai", canister_ids[0].to_string());
 
         let uri = "/-/r5m5i-tiaaa-aaaaj-acgaq-cai/-/1/ex/yx?q1=23&q2=33"
             .parse::<Uri>()
@@ -340,9 +967,9 @@ mod test {
         let res =
             resolve_canister_id_from_uri(&uri, None, None, canister_resolver.clone(), &logger)
                 .await;
-        let (canister_id, uri) = res.unwrap();
+        let (canister_ids, uri) = res.unwrap();
         assert_eq!("/-/1/ex/yx?q1=23&q2=33", uri);
-        assert_eq!("r5m5i-tiaaa-aaaaj-acgaq-cai", canister_id.to_string());
+        assert_eq!("r5m5i-tiaaa-aaaaj-acgaq-cai", canister_ids[0].to_string());
 
         let uri = "/-/r5m5i-tiaaa-aaaaj-acgaq-cai/-/1/ex/yx?_raw"
             .parse::<Uri>()
@@ -350,9 +977,9 @@ mod test {
         let res =
             resolve_canister_id_from_uri(&uri, None, None, canister_resolver.clone(), &logger)
                 .await;
-        let (canister_id, uri) = res.unwrap();
+        let (canister_ids, uri) = res.unwrap();
         assert_eq!("/-/1/ex/yx?_raw", uri);
-        assert_eq!("r5m5i-tiaaa-aaaaj-acgaq-cai", canister_id.to_string());
+        assert_eq!("r5m5i-tiaaa-aaaaj-acgaq-cai", canister_ids[0].to_string());
 
         //https://nft.origyn.network/x/-/y => Error
         let uri = "/uefa_nfts4g/-/uefa_nfts4g_0".parse::<Uri>().unwrap();
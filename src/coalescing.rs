@@ -0,0 +1,535 @@
+use hyper::{
+    body::{Bytes, HttpBody},
+    header, Body, HeaderMap, Response, StatusCode,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::watch;
+
+// Request headers of the connection to the proxy, not used to build the response.
+const CONNECTION_HEADERS: [&str; 6] = [
+    "connection",
+    "content-length",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "transfer-encoding",
+];
+
+/// Single-flight of the identical concurrent requests. The first request of a key queries
+/// the canister, the requests with the same key received before the end of its body wait
+/// for its response. The chunks of the body are kept in a buffer shared by all the
+/// requests, each request sends them to its client at its own pace. When the first request
+/// is cancelled before the response is received, a waiting request queries the canister.
+///
+/// Once the buffer of a body is larger than `max_buffer_size`, no other request joins it:
+/// the next requests query the canister. The chunks sent by all the requests of the body
+/// are then dropped, and the body is received at the pace of the slowest request.
+pub struct Coalescer {
+    flights: Mutex<HashMap<String, Arc<Flight>>>,
+    max_buffer_size: usize,
+}
+
+// The response of the first request of a key, shared with the other requests.
+struct Flight {
+    state: Mutex<FlightState>,
+    //signals each change of the state.
+    changed: watch::Sender<()>,
+    receiver: watch::Receiver<()>,
+}
+
+struct FlightState {
+    //the status and the headers of the response, or the error of the request.
+    head: Option<Result<(StatusCode, HeaderMap), String>>,
+    //the first request was dropped before the response was received.
+    cancelled: bool,
+    //the chunks of the body not yet sent by all the requests, `first` is the index of the
+    //first one in the body and `size` their size in bytes.
+    chunks: VecDeque<Bytes>,
+    first: usize,
+    size: usize,
+    //the index of the next chunk of each request, `None` once it has stopped reading.
+    readers: Vec<Option<usize>>,
+    //other requests can join, the chunks are kept from the start of the body.
+    shared: bool,
+    end: Option<BodyEnd>,
+}
+
+// A request reading the body of a flight, it stops reading when dropped.
+struct Reader {
+    flight: Arc<Flight>,
+    index: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BodyEnd {
+    Complete,
+    Aborted,
+}
+
+// Ends the flight of the first request: the waiting requests send the request again if it
+// is dropped before the response is received, and the key is free for the next requests
+// once the body is received.
+struct LeaderGuard {
+    coalescer: Arc<Coalescer>,
+    key: String,
+    flight: Arc<Flight>,
+}
+
+impl Coalescer {
+    pub fn new(max_buffer_size: usize) -> Self {
+        Coalescer {
+            flights: Mutex::new(HashMap::new()),
+            max_buffer_size,
+        }
+    }
+
+    /// Send the request, or wait for the response of the identical request in flight.
+    pub async fn coalesce<F>(
+        self: &Arc<Self>,
+        key: String,
+        request: F,
+    ) -> Result<Response<Body>, Box<dyn Error>>
+    where
+        F: Future<Output = Result<Response<Body>, Box<dyn Error>>>,
+    {
+        loop {
+            //a request joins the flight before its key is removed, no chunk is dropped before
+            //it reads it.
+            let (flight, reader, leader) = {
+                let mut flights = self.flights.lock().unwrap();
+                match flights.get(&key) {
+                    Some(flight) => (flight.clone(), flight.join(), false),
+                    None => {
+                        let flight = Arc::new(Flight::new());
+                        flights.insert(key.clone(), flight.clone());
+                        (flight.clone(), flight.join(), true)
+                    }
+                }
+            };
+            if leader {
+                let guard = LeaderGuard {
+                    coalescer: self.clone(),
+                    key,
+                    flight: flight.clone(),
+                };
+                return match request.await {
+                    Ok(response) => {
+                        let (parts, body) = response.into_parts();
+                        flight.update(|state| {
+                            state.head = Some(Ok((parts.status, parts.headers.clone())))
+                        });
+                        tokio::spawn(guard.buffer(body));
+                        Ok(reader.response(parts.status, parts.headers))
+                    }
+                    Err(err) => {
+                        flight.update(|state| state.head = Some(Err(err.to_string())));
+                        Err(err)
+                    }
+                };
+            }
+            //the request is sent again when the first request is cancelled.
+            if let Some(head) = flight.head().await {
+                let (status, headers) = head?;
+                return Ok(reader.response(status, headers));
+            }
+        }
+    }
+
+    fn remove(&self, key: &str, flight: &Arc<Flight>) {
+        let mut flights = self.flights.lock().unwrap();
+        if flights
+            .get(key)
+            .map_or(false, |current| Arc::ptr_eq(current, flight))
+        {
+            flights.remove(key);
+        }
+    }
+}
+
+/// The part of the key of a request built from its headers: all the headers sent to the
+/// canister, except the headers of the connection. `None` for the requests with credentials
+/// or a body, they are not shared.
+pub fn headers_key(headers: &HeaderMap) -> Option<String> {
+    if headers.contains_key(header::AUTHORIZATION)
+        || headers.contains_key(header::COOKIE)
+        || headers.contains_key(header::TRANSFER_ENCODING)
+        || headers
+            .get(header::CONTENT_LENGTH)
+            .map_or(false, |length| length != "0")
+    {
+        return None;
+    }
+    //sorted by name, the values of a header are kept in order.
+    let mut fields = headers
+        .iter()
+        .filter(|(name, _)| !CONNECTION_HEADERS.contains(&name.as_str()))
+        .collect::<Vec<_>>();
+    fields.sort_by_key(|(name, _)| name.as_str());
+    let mut key = String::new();
+    for (name, value) in fields {
+        key.push('\n');
+        key.push_str(name.as_str());
+        key.push(':');
+        key.push_str(&String::from_utf8_lossy(value.as_bytes()));
+    }
+    Some(key)
+}
+
+impl Flight {
+    fn new() -> Self {
+        let (changed, receiver) = watch::channel(());
+        Flight {
+            state: Mutex::new(FlightState {
+                head: None,
+                cancelled: false,
+                chunks: VecDeque::new(),
+                first: 0,
+                size: 0,
+                readers: Vec::new(),
+                shared: true,
+                end: None,
+            }),
+            changed,
+            receiver,
+        }
+    }
+
+    // Add a request reading the body from its start.
+    fn join(self: &Arc<Self>) -> Reader {
+        let mut state = self.state.lock().unwrap();
+        state.readers.push(Some(0));
+        Reader {
+            flight: self.clone(),
+            index: state.readers.len() - 1,
+        }
+    }
+
+    fn update(&self, update: impl FnOnce(&mut FlightState)) {
+        update(&mut self.state.lock().unwrap());
+        let _ = self.changed.send(());
+    }
+
+    // The head of the response once it's received, `None` if the first request is cancelled.
+    async fn head(&self) -> Option<Result<(StatusCode, HeaderMap), String>> {
+        let mut receiver = self.receiver.clone();
+        loop {
+            {
+                let state = self.state.lock().unwrap();
+                if state.head.is_some() || state.cancelled {
+                    return state.head.clone();
+                }
+            }
+            if receiver.changed().await.is_err() {
+                return None;
+            }
+        }
+    }
+}
+
+impl FlightState {
+    // Drop the chunks sent by all the requests, once no other request can join.
+    fn trim(&mut self) {
+        if self.shared {
+            return;
+        }
+        let read = self
+            .readers
+            .iter()
+            .flatten()
+            .min()
+            .copied()
+            .unwrap_or(self.first + self.chunks.len());
+        while self.first < read {
+            if let Some(chunk) = self.chunks.pop_front() {
+                self.size -= chunk.len();
+            }
+            self.first += 1;
+        }
+    }
+}
+
+impl Reader {
+    // The response with this head, its body is sent from the shared buffer.
+    fn response(self, status: StatusCode, headers: HeaderMap) -> Response<Body> {
+        let mut receiver = self.flight.receiver.clone();
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            let mut next = 0;
+            loop {
+                let (chunk, end) = {
+                    let state = self.flight.state.lock().unwrap();
+                    (state.chunks.get(next - state.first).cloned(), state.end)
+                };
+                if let Some(chunk) = chunk {
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                    next += 1;
+                    self.flight.update(|state| {
+                        state.readers[self.index] = Some(next);
+                        state.trim();
+                    });
+                    continue;
+                }
+                match end {
+                    Some(BodyEnd::Complete) => return,
+                    Some(BodyEnd::Aborted) => {
+                        sender.abort();
+                        return;
+                    }
+                    None => {
+                        if receiver.changed().await.is_err() {
+                            sender.abort();
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+
+        let mut response = Response::new(body);
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+        response
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        self.flight.update(|state| {
+            state.readers[self.index] = None;
+            state.trim();
+        });
+    }
+}
+
+impl LeaderGuard {
+    // Receive the body into the shared buffer. Past the maximum size of the buffer, the
+    // next chunk is received once the requests have sent enough chunks.
+    async fn buffer(self, mut body: Body) {
+        let max_buffer_size = self.coalescer.max_buffer_size;
+        let mut receiver = self.flight.receiver.clone();
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(_) => return,
+            };
+            let mut full = false;
+            self.flight.update(|state| {
+                state.size += chunk.len();
+                state.chunks.push_back(chunk);
+                full = state.shared && state.size > max_buffer_size;
+            });
+            if full {
+                self.coalescer.remove(&self.key, &self.flight);
+                self.flight.update(|state| {
+                    state.shared = false;
+                    state.trim();
+                });
+            }
+            while self.flight.state.lock().unwrap().size > max_buffer_size {
+                if receiver.changed().await.is_err() {
+                    return;
+                }
+            }
+        }
+        self.coalescer.remove(&self.key, &self.flight);
+        self.flight.update(|state| {
+            state.shared = false;
+            state.trim();
+            state.end = Some(BodyEnd::Complete);
+        });
+    }
+}
+
+impl Drop for LeaderGuard {
+    fn drop(&mut self) {
+        self.coalescer.remove(&self.key, &self.flight);
+        self.flight.update(|state| {
+            state.shared = false;
+            state.trim();
+            if state.head.is_none() {
+                state.cancelled = true;
+            }
+            if state.end.is_none() {
+                state.end = Some(BodyEnd::Aborted);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let coalescer = Arc::new(Coalescer::new(1024));
+        let requests = Arc::new(AtomicUsize::new(0));
+        let (mut sender, body) = Body::channel();
+        let (first, second) = {
+            let requests = requests.clone();
+            let request = |body| {
+                let requests = requests.clone();
+                async move {
+                    requests.fetch_add(1, Ordering::Relaxed);
+                    let response: Result<_, Box<dyn Error>> = Ok(Response::new(body));
+                    response
+                }
+            };
+            let first = coalescer.coalesce("key".to_string(), request(body));
+            let second = coalescer.coalesce("key".to_string(), request(Body::empty()));
+            tokio::join!(first, second)
+        };
+        assert_eq!(1, requests.load(Ordering::Relaxed));
+
+        //both requests receive the streamed body.
+        sender.send_data(Bytes::from("hello ")).await.unwrap();
+        sender.send_data(Bytes::from("world")).await.unwrap();
+        drop(sender);
+        let first = hyper::body::to_bytes(first.unwrap().into_body()).await;
+        let second = hyper::body::to_bytes(second.unwrap().into_body()).await;
+        assert_eq!(Bytes::from("hello world"), first.unwrap());
+        assert_eq!(Bytes::from("hello world"), second.unwrap());
+
+        //the key is free once the body is received.
+        let response = coalescer
+            .coalesce("key".to_string(), async {
+                Ok(Response::new(Body::from("again")))
+            })
+            .await;
+        let body = hyper::body::to_bytes(response.unwrap().into_body()).await;
+        assert_eq!(Bytes::from("again"), body.unwrap());
+    }
+
+    #[test]
+    fn test_headers_key() {
+        let headers = |fields: &[(&str, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in fields {
+                headers.append(
+                    header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                    value.parse().unwrap(),
+                );
+            }
+            headers_key(&headers)
+        };
+        let key = headers(&[("accept", "image/webp"), ("accept-language", "fr")]);
+        assert!(key.is_some());
+        //the order of the headers and the connection headers are not part of the key.
+        assert_eq!(
+            key,
+            headers(&[
+                ("connection", "keep-alive"),
+                ("accept-language", "fr"),
+                ("accept", "image/webp"),
+            ])
+        );
+        assert_ne!(
+            key,
+            headers(&[("accept", "image/webp"), ("accept-language", "en")])
+        );
+        assert_ne!(
+            key,
+            headers(&[
+                ("accept", "image/webp"),
+                ("accept-language", "fr"),
+                ("x-custom", "1"),
+            ])
+        );
+        assert_eq!(None, headers(&[("cookie", "session=1")]));
+        assert_eq!(None, headers(&[("content-length", "10")]));
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_cancelled() {
+        let coalescer = Arc::new(Coalescer::new(1024));
+        //the first request is dropped while the second one is waiting for its response.
+        let mut first = Box::pin(coalescer.coalesce(
+            "key".to_string(),
+            std::future::pending::<Result<Response<Body>, Box<dyn Error>>>(),
+        ));
+        let timeout = tokio::time::timeout(std::time::Duration::from_millis(10), &mut first);
+        assert!(timeout.await.is_err());
+        let second = coalescer.coalesce("key".to_string(), async {
+            Ok(Response::new(Body::from("second")))
+        });
+        let (second, _) = tokio::join!(second, async {
+            tokio::task::yield_now().await;
+            drop(first);
+        });
+        let body = hyper::body::to_bytes(second.unwrap().into_body()).await;
+        assert_eq!(Bytes::from("second"), body.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_error() {
+        let coalescer = Arc::new(Coalescer::new(1024));
+        let (sender, receiver) = tokio::sync::oneshot::channel::<()>();
+        //the first request fails once the second one is waiting.
+        let first = coalescer.coalesce("key".to_string(), async {
+            receiver.await.unwrap();
+            Err("Unable to fetch root key".into())
+        });
+        let second = coalescer.coalesce("key".to_string(), async {
+            Ok(Response::new(Body::empty()))
+        });
+        let (first, second, _) = tokio::join!(first, second, async { sender.send(()) });
+        assert_eq!("Unable to fetch root key", first.err().unwrap().to_string());
+        assert_eq!(
+            "Unable to fetch root key",
+            second.err().unwrap().to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_max_buffer_size() {
+        let coalescer = Arc::new(Coalescer::new(8));
+        let requests = Arc::new(AtomicUsize::new(0));
+        let request = |body| {
+            let requests = requests.clone();
+            async move {
+                requests.fetch_add(1, Ordering::Relaxed);
+                let response: Result<_, Box<dyn Error>> = Ok(Response::new(body));
+                response
+            }
+        };
+        let (mut sender, body) = Body::channel();
+        let (first, second) = tokio::join!(
+            coalescer.coalesce("key".to_string(), request(body)),
+            coalescer.coalesce("key".to_string(), request(Body::empty()))
+        );
+        let (mut first, mut second) = (first.unwrap().into_body(), second.unwrap().into_body());
+        sender.send_data(Bytes::from("hello world")).await.unwrap();
+        assert_eq!(
+            Bytes::from("hello world"),
+            first.data().await.unwrap().unwrap()
+        );
+
+        //past the size of the buffer, the next requests query the canister.
+        let third = coalescer
+            .coalesce("key".to_string(), request(Body::from("third")))
+            .await;
+        assert_eq!(2, requests.load(Ordering::Relaxed));
+        let third = hyper::body::to_bytes(third.unwrap().into_body()).await;
+        assert_eq!(Bytes::from("third"), third.unwrap());
+
+        //the requests already waiting still receive the whole body.
+        sender.send_data(Bytes::from("!")).await.unwrap();
+        drop(sender);
+        assert_eq!(
+            Bytes::from("hello world"),
+            second.data().await.unwrap().unwrap()
+        );
+        let first = hyper::body::to_bytes(first).await;
+        let second = hyper::body::to_bytes(second).await;
+        assert_eq!(Bytes::from("!"), first.unwrap());
+        assert_eq!(Bytes::from("!"), second.unwrap());
+    }
+}
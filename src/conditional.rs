@@ -0,0 +1,198 @@
+use crate::body_hash::{codings_name, BodyHasher, DecompressionLimits};
+use hyper::{header, http::response::Builder, HeaderMap, Response, StatusCode};
+use ic_utils::interfaces::http_request::HeaderField;
+
+// Response headers also sent with a `304 Not Modified`.
+const NOT_MODIFIED_HEADERS: [header::HeaderName; 5] = [
+    header::CACHE_CONTROL,
+    header::CONTENT_LOCATION,
+    header::DATE,
+    header::EXPIRES,
+    header::VARY,
+];
+
+/// A strong entity tag built from the certified sha256 of a body. The codings of an
+/// encoded body are added after the hash, the same content has a different tag for each
+/// encoding. `None` for a coding that can't be part of a tag.
+pub fn certified_etag(sha: &[u8], encoding: Option<&str>) -> Option<String> {
+    let codings = codings_name(encoding)?;
+    if codings.is_empty() {
+        Some(format!("\"{}\"", hex::encode(sha)))
+    } else {
+        Some(format!("\"{}-{}\"", hex::encode(sha), codings))
+    }
+}
+
+/// The entity tag of a complete body, from the sha256 of the body decoded with its
+/// `Content-Encoding` like the certified hash. `None` when the body can't be decoded.
+pub fn body_etag(
+    body: &[u8],
+    encoding: Option<&str>,
+    limits: DecompressionLimits,
+) -> Option<String> {
+    let mut hasher = BodyHasher::new(encoding, limits).ok()?;
+    hasher.update(body).ok()?;
+    certified_etag(&hasher.finish().ok()?, encoding)
+}
+
+/// The `ETag` header of the canister response.
+pub fn response_etag<'a>(response_headers: &'a [HeaderField]) -> Option<&'a str> {
+    response_header(response_headers, header::ETAG.as_str())
+}
+
+/// The request can be answered with a `304 Not Modified`: one of the tags of
+/// `If-None-Match` matches the entity tag of the response or, without `If-None-Match`, the
+/// response was not modified after the `If-Modified-Since` date.
+pub fn is_not_modified(
+    request_headers: &HeaderMap,
+    response_headers: &[HeaderField],
+    etag: Option<&str>,
+) -> bool {
+    if let Some(if_none_match) = request_headers.get(header::IF_NONE_MATCH) {
+        let etag = match etag {
+            Some(etag) => etag,
+            None => return false,
+        };
+        return if_none_match.to_str().map_or(false, |if_none_match| {
+            if_none_match_matches(if_none_match, etag)
+        });
+    }
+    let if_modified_since = request_headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|date| date.to_str().ok())
+        .and_then(|date| httpdate::parse_http_date(date).ok());
+    let last_modified = response_header(response_headers, header::LAST_MODIFIED.as_str())
+        .and_then(|date| httpdate::parse_http_date(date).ok());
+    match (if_modified_since, last_modified) {
+        (Some(if_modified_since), Some(last_modified)) => last_modified <= if_modified_since,
+        _ => false,
+    }
+}
+
+/// A `304 Not Modified` without body, with the entity tag and the caching headers of the
+/// response.
+pub fn not_modified_response(response_headers: &[HeaderField], etag: Option<&str>) -> Builder {
+    let mut builder = Response::builder().status(StatusCode::NOT_MODIFIED);
+    for HeaderField(name, value) in response_headers {
+        if NOT_MODIFIED_HEADERS
+            .iter()
+            .any(|header_name| name.eq_ignore_ascii_case(header_name.as_str()))
+        {
+            builder = builder.header(name.as_ref(), value.as_ref());
+        }
+    }
+    if let Some(etag) = etag {
+        builder = builder.header(header::ETAG, etag);
+    }
+    builder
+}
+
+fn response_header<'a>(headers: &'a [HeaderField], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|HeaderField(header_name, _)| header_name.eq_ignore_ascii_case(name))
+        .map(|HeaderField(_, value)| value.as_ref())
+}
+
+// `If-None-Match` is `*` or a list of entity tags compared with the weak comparison:
+// the tags match when their values are equal, weak or not.
+fn if_none_match_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque_tag = |tag: &str| {
+        let tag = tag.trim();
+        tag.strip_prefix("W/").unwrap_or(tag).to_string()
+    };
+    let etag = opaque_tag(etag);
+    if_none_match.trim() == "*" || if_none_match.split(',').any(|tag| opaque_tag(tag) == etag)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use sha2::{Digest, Sha256};
+    use std::io::Write;
+
+    #[test]
+    fn test_certified_etag() {
+        let sha = [0xab; 32];
+        assert_eq!(
+            Some(format!("\"{}\"", "ab".repeat(32))),
+            certified_etag(&sha, Some("identity"))
+        );
+        assert_eq!(
+            Some(format!("\"{}-gzip\"", "ab".repeat(32))),
+            certified_etag(&sha, Some("gzip"))
+        );
+        assert_eq!(None, certified_etag(&sha, Some("gz\"ip")));
+    }
+
+    #[test]
+    fn test_body_etag() {
+        let body = b"hello world".repeat(100);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&body).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let limits = DecompressionLimits::default();
+        let sha = hex::encode(Sha256::digest(&body));
+        assert_eq!(Some(format!("\"{}\"", sha)), body_etag(&body, None, limits));
+        //the tag of the encoded body is the hash of the decoded body and the codings.
+        assert_eq!(
+            Some(format!("\"{}-gzip\"", sha)),
+            body_etag(&gzipped, Some("gzip"), limits)
+        );
+        assert_eq!(None, body_etag(&body, Some("gzip"), limits));
+    }
+
+    #[test]
+    fn test_is_not_modified() {
+        let request = |name: header::HeaderName, value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, value.parse().unwrap());
+            headers
+        };
+        let last_modified = [HeaderField(
+            "Last-Modified".into(),
+            "Wed, 21 Oct 2015 07:28:00 GMT".into(),
+        )];
+
+        let headers = request(header::IF_NONE_MATCH, "\"a\", W/\"b\"");
+        assert!(is_not_modified(&headers, &[], Some("\"a\"")));
+        assert!(is_not_modified(&headers, &[], Some("\"b\"")));
+        assert!(!is_not_modified(&headers, &[], Some("\"c\"")));
+        assert!(!is_not_modified(&headers, &[], None));
+        let headers = request(header::IF_NONE_MATCH, "*");
+        assert!(is_not_modified(&headers, &[], Some("\"c\"")));
+        assert!(!is_not_modified(&HeaderMap::new(), &[], Some("\"a\"")));
+
+        let headers = request(header::IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT");
+        assert!(is_not_modified(&headers, &last_modified, None));
+        let headers = request(header::IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:27:59 GMT");
+        assert!(!is_not_modified(&headers, &last_modified, None));
+        assert!(!is_not_modified(&headers, &[], None));
+
+        //`If-Modified-Since` is ignored with `If-None-Match`.
+        let mut headers = request(header::IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT");
+        headers.insert(header::IF_NONE_MATCH, "\"b\"".parse().unwrap());
+        assert!(!is_not_modified(&headers, &last_modified, Some("\"a\"")));
+    }
+
+    #[test]
+    fn test_not_modified_response() {
+        let headers = [
+            HeaderField("Cache-Control".into(), "max-age=60".into()),
+            HeaderField("Content-Length".into(), "1000".into()),
+            HeaderField("Content-Type".into(), "image/png".into()),
+        ];
+        let response = not_modified_response(&headers, Some("\"a\""))
+            .body(hyper::Body::empty())
+            .unwrap();
+        assert_eq!(StatusCode::NOT_MODIFIED, response.status());
+        assert_eq!("\"a\"", response.headers().get(header::ETAG).unwrap());
+        assert_eq!(
+            "max-age=60",
+            response.headers().get(header::CACHE_CONTROL).unwrap()
+        );
+        assert!(response.headers().get(header::CONTENT_LENGTH).is_none());
+    }
+}
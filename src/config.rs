@@ -0,0 +1,105 @@
+use ic_agent::export::Principal;
+use std::{collections::HashMap, fs, path::Path};
+
+/// Static map of custom domains and path aliases to canister ids.
+/// Entries have the format `name:canister-id`. A name containing a dot is a domain
+/// matched against the Host header (case insensitive), otherwise it's a path alias.
+#[derive(Clone, Debug, Default)]
+pub struct DnsAliasConfig {
+    domains: HashMap<String, Principal>,
+    aliases: HashMap<String, Principal>,
+}
+
+impl DnsAliasConfig {
+    /// Build the map from the `--dns-alias` entries and the optional `--dns-alias-file`.
+    /// The file contains one entry per line, empty lines and lines starting with `#` are ignored.
+    pub fn new(entries: &[String], file: Option<&Path>) -> Result<Self, String> {
+        let file_content = match file {
+            Some(path) => fs::read_to_string(path).map_err(|err| {
+                format!(
+                    "Error could not read dns alias file {}: {}",
+                    path.display(),
+                    err
+                )
+            })?,
+            None => String::new(),
+        };
+
+        let mut config = DnsAliasConfig::default();
+        let file_entries = file_content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        for entry in entries.iter().map(|entry| entry.trim()).chain(file_entries) {
+            let (name, canister_id) = parse_entry(entry)?;
+            if name.contains('.') {
+                config
+                    .domains
+                    .insert(name.to_ascii_lowercase(), canister_id);
+            } else {
+                config.aliases.insert(name.to_string(), canister_id);
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn resolve_alias(&self, alias: &str) -> Option<Principal> {
+        self.aliases.get(alias).copied()
+    }
+
+    pub fn resolve_domain(&self, domain: &str) -> Option<Principal> {
+        self.domains.get(&domain.to_ascii_lowercase()).copied()
+    }
+}
+
+fn parse_entry(entry: &str) -> Result<(&str, Principal), String> {
+    let (name, canister_id) = entry.rsplit_once(':').ok_or_else(|| {
+        format!(
+            "Error dns alias '{}' is not in the format name:canister-id",
+            entry
+        )
+    })?;
+    let name = name.trim().trim_end_matches('.');
+    if name.is_empty() {
+        return Err(format!("Error dns alias '{}' has an empty name", entry));
+    }
+    let canister_id = Principal::from_text(canister_id.trim()).map_err(|err| {
+        format!(
+            "Error dns alias '{}' canister id not a principal: {}",
+            entry, err
+        )
+    })?;
+    Ok((name, canister_id))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dns_alias_config() {
+        let entries = vec![
+            "uefa_nfts4g:r5m5i-tiaaa-aaaaj-acgaq-cai".to_string(),
+            " NFT.Example.com : ngrpb-5qaaa-aaaaj-adz7a-cai ".to_string(),
+        ];
+        let config = DnsAliasConfig::new(&entries, None).unwrap();
+        assert_eq!(
+            "r5m5i-tiaaa-aaaaj-acgaq-cai",
+            config.resolve_alias("uefa_nfts4g").unwrap().to_string()
+        );
+        assert_eq!(
+            "ngrpb-5qaaa-aaaaj-adz7a-cai",
+            config
+                .resolve_domain("nft.example.COM")
+                .unwrap()
+                .to_string()
+        );
+        //domains and aliases are separated.
+        assert!(config.resolve_domain("uefa_nfts4g").is_none());
+        assert!(config.resolve_alias("nft.example.com").is_none());
+
+        assert!(DnsAliasConfig::new(&["uefa_nfts4g".to_string()], None).is_err());
+        assert!(DnsAliasConfig::new(&[":r5m5i-tiaaa-aaaaj-acgaq-cai".to_string()], None).is_err());
+        assert!(DnsAliasConfig::new(&["uefa_nfts4g:uefa_nfts4g".to_string()], None).is_err());
+    }
+}
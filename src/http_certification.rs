@@ -0,0 +1,344 @@
+use ic_utils::interfaces::http_request::HeaderField;
+use sha2::{Digest, Sha256};
+
+pub const IC_CERTIFICATE_HEADER: &str = "ic-certificate";
+pub const IC_CERTIFICATE_EXPRESSION_HEADER: &str = "ic-certificateexpression";
+
+// Pseudo headers of the request and response hashes.
+const CERT_STATUS: &str = ":ic-cert-status";
+const CERT_METHOD: &str = ":ic-cert-method";
+const CERT_QUERY: &str = ":ic-cert-query";
+
+/// Parsed `IC-CertificateExpression` header of the response certification v2.
+/// `certification` is `None` for `no_certification`.
+#[derive(Debug, PartialEq)]
+pub struct CertificateExpression {
+    pub certification: Option<Certification>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Certification {
+    pub request: Option<RequestCertification>,
+    pub response: ResponseCertification,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RequestCertification {
+    pub headers: Vec<String>,
+    pub query_parameters: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ResponseCertification {
+    /// Only these response headers are certified.
+    CertifiedHeaders(Vec<String>),
+    /// All the response headers are certified except these ones.
+    HeaderExclusions(Vec<String>),
+}
+
+impl CertificateExpression {
+    /// Parse the `default_certification(ValidationArgs{...})` expression.
+    /// Header names are lowercased.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let expression = value
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>();
+        let unsupported = || format!("Unsupported certificate expression: {}", value);
+        let args = expression
+            .strip_prefix("default_certification(ValidationArgs{")
+            .and_then(|args| args.strip_suffix("})"))
+            .ok_or_else(unsupported)?;
+        if args.starts_with("no_certification:Empty{}") {
+            return Ok(CertificateExpression {
+                certification: None,
+            });
+        }
+        if !args.starts_with("certification:Certification{") {
+            return Err(unsupported());
+        }
+
+        let request = if args.contains("no_request_certification:Empty{}") {
+            None
+        } else {
+            Some(RequestCertification {
+                headers: parse_list(args, "certified_request_headers").ok_or_else(unsupported)?,
+                query_parameters: parse_list(args, "certified_query_parameters")
+                    .ok_or_else(unsupported)?,
+            })
+        };
+        let response = match (
+            parse_list(
+                args,
+                "certified_response_headers:ResponseHeaderList{headers",
+            ),
+            parse_list(
+                args,
+                "response_header_exclusions:ResponseHeaderList{headers",
+            ),
+        ) {
+            (Some(headers), None) => ResponseCertification::CertifiedHeaders(headers),
+            (None, Some(headers)) => ResponseCertification::HeaderExclusions(headers),
+            _ => return Err(unsupported()),
+        };
+        Ok(CertificateExpression {
+            certification: Some(Certification { request, response }),
+        })
+    }
+}
+
+// The `["a","b"]` list of a field.
+fn parse_list(expression: &str, field: &str) -> Option<Vec<String>> {
+    let field = format!("{}:[", field);
+    let start = expression.find(&field)? + field.len();
+    let end = start + expression[start..].find(']')?;
+    Some(
+        expression[start..end]
+            .split(',')
+            .map(|name| name.trim_matches('"').to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect(),
+    )
+}
+
+enum Value<'a> {
+    Text(&'a str),
+    Blob(&'a [u8]),
+    Nat(u64),
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+fn hash_value(value: &Value) -> [u8; 32] {
+    match value {
+        Value::Text(text) => sha256(text.as_bytes()),
+        Value::Blob(blob) => sha256(blob),
+        Value::Nat(mut nat) => {
+            let mut leb128 = Vec::new();
+            loop {
+                let byte = (nat & 0x7f) as u8;
+                nat >>= 7;
+                if nat == 0 {
+                    leb128.push(byte);
+                    break;
+                }
+                leb128.push(byte | 0x80);
+            }
+            sha256(&leb128)
+        }
+    }
+}
+
+// The representation-independent hash of a map, as defined by the IC interface specification.
+fn representation_independent_hash(map: &[(String, Value)]) -> [u8; 32] {
+    let mut hashes = map
+        .iter()
+        .map(|(key, value)| [sha256(key.as_bytes()), hash_value(value)].concat())
+        .collect::<Vec<_>>();
+    hashes.sort();
+    sha256(&hashes.concat())
+}
+
+/// Hash of the request method, the certified request headers and query parameters, and the body.
+pub fn request_hash(
+    certification: &RequestCertification,
+    method: &str,
+    url: &str,
+    headers: &[HeaderField],
+    body: &[u8],
+) -> [u8; 32] {
+    let mut map = headers
+        .iter()
+        .map(|HeaderField(name, value)| (name.to_ascii_lowercase(), value))
+        .filter(|(name, _)| certification.headers.contains(name))
+        .map(|(name, value)| (name, Value::Text(value)))
+        .collect::<Vec<_>>();
+    map.push((CERT_METHOD.to_string(), Value::Text(method)));
+
+    let query = url
+        .split_once('?')
+        .map(|(_, query)| query)
+        .unwrap_or_default()
+        .split('&')
+        .filter(|parameter| {
+            let name = parameter.split('=').next().unwrap_or_default();
+            certification
+                .query_parameters
+                .iter()
+                .any(|certified| certified == name)
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    let query_hash = sha256(query.as_bytes());
+    if !query.is_empty() {
+        map.push((CERT_QUERY.to_string(), Value::Blob(&query_hash)));
+    }
+
+    sha256(&[representation_independent_hash(&map), sha256(body)].concat())
+}
+
+/// Hash of the status code and the certified response headers.
+/// The `IC-CertificateExpression` header is always certified, the `IC-Certificate` never.
+pub fn response_headers_hash(
+    certification: &ResponseCertification,
+    status_code: u16,
+    headers: &[HeaderField],
+) -> [u8; 32] {
+    let mut map = headers
+        .iter()
+        .map(|HeaderField(name, value)| (name.to_ascii_lowercase(), value))
+        .filter(|(name, _)| {
+            name == IC_CERTIFICATE_EXPRESSION_HEADER
+                || match certification {
+                    ResponseCertification::CertifiedHeaders(certified) => certified.contains(name),
+                    ResponseCertification::HeaderExclusions(excluded) => {
+                        name != IC_CERTIFICATE_HEADER && !excluded.contains(name)
+                    }
+                }
+        })
+        .map(|(name, value)| (name, Value::Text(value)))
+        .collect::<Vec<_>>();
+    map.push((CERT_STATUS.to_string(), Value::Nat(status_code.into())));
+
+    representation_independent_hash(&map)
+}
+
+/// Hash of the status code, the certified response headers and the body hash,
+/// from the hash of the status code and headers of `response_headers_hash`.
+pub fn response_hash_with_body(headers_hash: &[u8; 32], body_sha: &[u8; 32]) -> [u8; 32] {
+    sha256(&[*headers_hash, *body_sha].concat())
+}
+
+/// Hash of the expression, the label of the certified responses of an expression in the tree.
+pub fn expression_hash(expression: &str) -> [u8; 32] {
+    sha256(expression.as_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_certificate_expression() {
+        let expression = CertificateExpression::parse(
+            "default_certification(ValidationArgs{no_certification:Empty{}})",
+        )
+        .unwrap();
+        assert_eq!(None, expression.certification);
+
+        let expression = CertificateExpression::parse(
+            r#"default_certification(ValidationArgs {
+                certification: Certification {
+                    no_request_certification: Empty {},
+                    response_certification: ResponseCertification {
+                        certified_response_headers: ResponseHeaderList {
+                            headers: ["Content-Type", "content-encoding"]
+                        }
+                    }
+                }
+            })"#,
+        )
+        .unwrap();
+        assert_eq!(
+            Some(Certification {
+                request: None,
+                response: ResponseCertification::CertifiedHeaders(vec![
+                    "content-type".to_string(),
+                    "content-encoding".to_string()
+                ]),
+            }),
+            expression.certification
+        );
+
+        let expression = CertificateExpression::parse(
+            r#"default_certification(ValidationArgs{certification:Certification{request_certification:RequestCertification{certified_request_headers:["host"],certified_query_parameters:[]},response_certification:ResponseCertification{response_header_exclusions:ResponseHeaderList{headers:[]}}}})"#,
+        )
+        .unwrap();
+        assert_eq!(
+            Some(Certification {
+                request: Some(RequestCertification {
+                    headers: vec!["host".to_string()],
+                    query_parameters: vec![],
+                }),
+                response: ResponseCertification::HeaderExclusions(vec![]),
+            }),
+            expression.certification
+        );
+
+        assert!(CertificateExpression::parse("certification()").is_err());
+        assert!(CertificateExpression::parse(
+            "default_certification(ValidationArgs{certification:Certification{no_request_certification:Empty{}}})"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_representation_independent_hash() {
+        // Example of the IC interface specification.
+        let map = vec![
+            ("request_type".to_string(), Value::Text("call")),
+            (
+                "canister_id".to_string(),
+                Value::Blob(b"\x00\x00\x00\x00\x00\x00\x04\xD2"),
+            ),
+            ("method_name".to_string(), Value::Text("hello")),
+            ("arg".to_string(), Value::Blob(b"DIDL\x00\xFD*")),
+        ];
+        assert_eq!(
+            "8781291c347db32a9d8c10eb62b710fce5a93be676474c42babc74c51858f94b",
+            hex::encode(representation_independent_hash(&map))
+        );
+    }
+
+    fn response_hash(
+        certification: &ResponseCertification,
+        status_code: u16,
+        headers: &[HeaderField],
+        body_sha: &[u8; 32],
+    ) -> [u8; 32] {
+        response_hash_with_body(
+            &response_headers_hash(certification, status_code, headers),
+            body_sha,
+        )
+    }
+
+    #[test]
+    fn test_response_hash() {
+        let certification = ResponseCertification::CertifiedHeaders(vec!["content-type".into()]);
+        let body_sha = sha256(b"hello");
+        let headers = vec![
+            HeaderField("Content-Type".into(), "text/plain".into()),
+            HeaderField("IC-CertificateExpression".into(), "expr".into()),
+        ];
+        let hash = response_hash(&certification, 200, &headers, &body_sha);
+        //not certified headers do not change the hash.
+        let mut other_headers = headers.clone();
+        other_headers.push(HeaderField("X-Other".into(), "value".into()));
+        other_headers.push(HeaderField("IC-Certificate".into(), "certificate".into()));
+        assert_eq!(
+            hash,
+            response_hash(&certification, 200, &other_headers, &body_sha)
+        );
+        //the status, the certified headers and the body do.
+        assert_ne!(
+            hash,
+            response_hash(&certification, 404, &headers, &body_sha)
+        );
+        assert_ne!(
+            hash,
+            response_hash(&certification, 200, &headers[1..], &body_sha)
+        );
+        assert_ne!(
+            hash,
+            response_hash(&certification, 200, &headers, &sha256(b"bye"))
+        );
+
+        let certification = ResponseCertification::HeaderExclusions(vec![]);
+        assert_ne!(
+            response_hash(&certification, 200, &headers, &body_sha),
+            response_hash(&certification, 200, &other_headers, &body_sha)
+        );
+    }
+}
@@ -0,0 +1,300 @@
+use crate::req_validation::{ValidationError, Verification};
+use hyper::{http::response::Builder, Body, Response, StatusCode};
+use ic_agent::AgentError;
+
+/// Header containing the machine readable error code of an error response.
+pub const IC_ERROR_HEADER: &str = "X-IC-Error";
+/// Header containing the result of the validation of the canister response.
+pub const IC_VERIFICATION_HEADER: &str = "X-IC-Verification";
+
+// Reject codes of the IC interface specification.
+const REJECT_SYS_FATAL: u64 = 1;
+const REJECT_SYS_TRANSIENT: u64 = 2;
+const REJECT_DESTINATION_INVALID: u64 = 3;
+const REJECT_CANISTER_REJECT: u64 = 4;
+
+/// Classification of an agent error as an HTTP status and a stable error code.
+#[derive(Debug, PartialEq)]
+pub struct IcError {
+    pub status: StatusCode,
+    pub code: &'static str,
+}
+
+impl IcError {
+    pub fn from_agent_error(err: &AgentError) -> Self {
+        let (status, code) = match err {
+            AgentError::ReplicaError {
+                reject_code,
+                reject_message,
+            } => {
+                let message = reject_message.to_ascii_lowercase();
+                if message.contains("is stopped") || message.contains("is stopping") {
+                    (StatusCode::SERVICE_UNAVAILABLE, "canister_stopped")
+                } else if message.contains("out of cycles") {
+                    (StatusCode::SERVICE_UNAVAILABLE, "canister_out_of_cycles")
+                } else {
+                    match *reject_code {
+                        REJECT_DESTINATION_INVALID => (StatusCode::NOT_FOUND, "canister_not_found"),
+                        REJECT_SYS_TRANSIENT => {
+                            (StatusCode::SERVICE_UNAVAILABLE, "replica_transient_error")
+                        }
+                        REJECT_SYS_FATAL => (StatusCode::BAD_GATEWAY, "replica_fatal_error"),
+                        REJECT_CANISTER_REJECT => {
+                            (StatusCode::INTERNAL_SERVER_ERROR, "canister_reject")
+                        }
+                        _ => (StatusCode::INTERNAL_SERVER_ERROR, "canister_error"),
+                    }
+                }
+            }
+            AgentError::TimeoutWaitingForResponse() => {
+                (StatusCode::GATEWAY_TIMEOUT, "replica_timeout")
+            }
+            AgentError::TransportError(_) => (StatusCode::BAD_GATEWAY, "replica_unreachable"),
+            AgentError::HttpError(_) => (StatusCode::BAD_GATEWAY, "replica_http_error"),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, "agent_error"),
+        };
+        IcError { status, code }
+    }
+
+    pub fn from_validation_error(err: &ValidationError) -> Self {
+        match err {
+            ValidationError::Certificate(_) => IcError {
+                status: StatusCode::BAD_GATEWAY,
+                code: "certificate_verification_failed",
+            },
+            ValidationError::CertificateTime(_) => IcError {
+                status: StatusCode::BAD_GATEWAY,
+                code: "certificate_time_invalid",
+            },
+            ValidationError::Uncertified(_) => IcError {
+                status: StatusCode::BAD_GATEWAY,
+                code: "response_not_certified",
+            },
+            ValidationError::Body(_) => IcError {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                code: "body_verification_failed",
+            },
+        }
+    }
+}
+
+/// Build the response returned to the client for an agent error. The body is a json
+/// object with the error code and the error message. There is no information leak here
+/// because a user could use `dfx` to get the same reply.
+pub fn agent_error_response(err: &AgentError) -> Response<Body> {
+    let IcError { status, code } = IcError::from_agent_error(err);
+    let body = match err {
+        AgentError::ReplicaError {
+            reject_code,
+            reject_message,
+        } => serde_json::json!({
+            "error_code": code,
+            "reject_code": reject_code,
+            "message": reject_message,
+        }),
+        _ => serde_json::json!({
+            "error_code": code,
+            "message": err.to_string(),
+        }),
+    };
+    json_error(status, code)
+        .body(body.to_string().into())
+        .unwrap()
+}
+
+/// Build the response returned to the client when the root key can't be fetched from the
+/// replicas with `--fetch-root-key`, the certificates of the canister can't be verified.
+pub fn root_key_error_response(err: &AgentError) -> Response<Body> {
+    let code = "root_key_unavailable";
+    let body = serde_json::json!({
+        "error_code": code,
+        "message": format!("Unable to fetch root key: {}", err),
+    });
+    json_error(StatusCode::BAD_GATEWAY, code)
+        .body(body.to_string().into())
+        .unwrap()
+}
+
+/// Build the response returned to the client when the canister response fails validation.
+/// The body is a json object with the error code and the reason of the failure.
+pub fn validation_error_response(err: &ValidationError) -> Response<Body> {
+    let IcError { status, code } = IcError::from_validation_error(err);
+    let body = serde_json::json!({
+        "error_code": code,
+        "message": err.to_string(),
+    });
+    json_error(status, code)
+        .header(IC_VERIFICATION_HEADER, "failed")
+        .body(body.to_string().into())
+        .unwrap()
+}
+
+// The builder of a json error response with its error code.
+fn json_error(status: StatusCode, code: &str) -> Builder {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .header(IC_ERROR_HEADER, code)
+}
+
+/// Add the result of the validation to a served response. A failure served with
+/// `--skip-body-verification` also has the error code of the failure.
+pub fn with_verification(builder: Builder, verification: &Verification) -> Builder {
+    let builder = builder.header(IC_VERIFICATION_HEADER, verification.header_value());
+    match verification {
+        Verification::Failed(err) => {
+            builder.header(IC_ERROR_HEADER, IcError::from_validation_error(err).code)
+        }
+        _ => builder,
+    }
+}
+
+/// Add the verification of the first chunk to a streamed response. A partial stream has
+/// a certified first chunk, but a later chunk can be served without certification.
+pub fn with_stream_verification(
+    builder: Builder,
+    verification: &Verification,
+    partial: bool,
+) -> Builder {
+    match verification {
+        Verification::CertifiedV1 if partial => {
+            builder.header(IC_VERIFICATION_HEADER, "certified-v1-partial")
+        }
+        Verification::CertifiedV2 if partial => {
+            builder.header(IC_VERIFICATION_HEADER, "certified-v2-partial")
+        }
+        verification => with_verification(builder, verification),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn replica_error(reject_code: u64, reject_message: &str) -> AgentError {
+        AgentError::ReplicaError {
+            reject_code,
+            reject_message: reject_message.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_agent_error_mapping() {
+        let err = replica_error(3, "Canister r5m5i-tiaaa-aaaaj-acgaq-cai not found");
+        assert_eq!(
+            IcError {
+                status: StatusCode::NOT_FOUND,
+                code: "canister_not_found"
+            },
+            IcError::from_agent_error(&err)
+        );
+        let err = replica_error(5, "Canister r5m5i-tiaaa-aaaaj-acgaq-cai is stopped");
+        assert_eq!(
+            StatusCode::SERVICE_UNAVAILABLE,
+            IcError::from_agent_error(&err).status
+        );
+        assert_eq!("canister_stopped", IcError::from_agent_error(&err).code);
+        let err = replica_error(2, "Canister r5m5i-tiaaa-aaaaj-acgaq-cai is out of cycles");
+        assert_eq!(
+            "canister_out_of_cycles",
+            IcError::from_agent_error(&err).code
+        );
+        let err = replica_error(5, "Canister trapped explicitly");
+        assert_eq!(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            IcError::from_agent_error(&err).status
+        );
+        let err = AgentError::TimeoutWaitingForResponse();
+        assert_eq!(
+            StatusCode::GATEWAY_TIMEOUT,
+            IcError::from_agent_error(&err).status
+        );
+    }
+
+    #[test]
+    fn test_agent_error_response() {
+        let err = replica_error(3, "Canister r5m5i-tiaaa-aaaaj-acgaq-cai not found");
+        let response = agent_error_response(&err);
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        assert_eq!(
+            "canister_not_found",
+            response.headers().get(IC_ERROR_HEADER).unwrap()
+        );
+
+        let err = ValidationError::Certificate("Certificate verification failed.".to_string());
+        let response = validation_error_response(&err);
+        assert_eq!(StatusCode::BAD_GATEWAY, response.status());
+        assert_eq!(
+            "certificate_verification_failed",
+            response.headers().get(IC_ERROR_HEADER).unwrap()
+        );
+        assert_eq!(
+            "failed",
+            response.headers().get(IC_VERIFICATION_HEADER).unwrap()
+        );
+
+        let response = root_key_error_response(&AgentError::TimeoutWaitingForResponse());
+        assert_eq!(StatusCode::BAD_GATEWAY, response.status());
+        assert_eq!(
+            "root_key_unavailable",
+            response.headers().get(IC_ERROR_HEADER).unwrap()
+        );
+        assert_eq!(
+            "application/json",
+            response.headers().get(hyper::header::CONTENT_TYPE).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_with_verification() {
+        let response = with_verification(Response::builder(), &Verification::CertifiedV2)
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            "certified-v2",
+            response.headers().get(IC_VERIFICATION_HEADER).unwrap()
+        );
+        assert!(response.headers().get(IC_ERROR_HEADER).is_none());
+
+        let err = ValidationError::Body("Body does not pass verification".to_string());
+        let response = with_verification(Response::builder(), &Verification::Failed(err))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            "failed",
+            response.headers().get(IC_VERIFICATION_HEADER).unwrap()
+        );
+        assert_eq!(
+            "body_verification_failed",
+            response.headers().get(IC_ERROR_HEADER).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_with_stream_verification() {
+        let response =
+            with_stream_verification(Response::builder(), &Verification::CertifiedV1, false)
+                .body(Body::empty())
+                .unwrap();
+        assert_eq!(
+            "certified-v1",
+            response.headers().get(IC_VERIFICATION_HEADER).unwrap()
+        );
+        let response =
+            with_stream_verification(Response::builder(), &Verification::CertifiedV2, true)
+                .body(Body::empty())
+                .unwrap();
+        assert_eq!(
+            "certified-v2-partial",
+            response.headers().get(IC_VERIFICATION_HEADER).unwrap()
+        );
+        let response =
+            with_stream_verification(Response::builder(), &Verification::Uncertified, true)
+                .body(Body::empty())
+                .unwrap();
+        assert_eq!(
+            "uncertified",
+            response.headers().get(IC_VERIFICATION_HEADER).unwrap()
+        );
+    }
+}
@@ -12,6 +12,12 @@ pub struct HeadersData {
 	pub tree: Option<Result<Vec<u8>, ()>>,
 	pub encoding: Option<String>,
 	pub key: Option<String>,
+	/// `version` of the `IC-Certificate` header, absent for the v1 certification.
+	pub version: Option<u16>,
+	/// CBOR encoded `expr_path` of the v2 certification.
+	pub expr_path: Option<Result<Vec<u8>, ()>>,
+	/// `IC-CertificateExpression` header of the v2 certification.
+	pub certificate_expression: Option<String>,
 }
 
 pub trait DataExtractor {
@@ -25,6 +31,9 @@ impl DataExtractor for HeadersData {
 				tree: None,
 				encoding: None,
 				key: None,
+				version: None,
+				expr_path: None,
+				certificate_expression: None,
 		};
 
 		for HeaderField(name, value) in headers {
@@ -78,9 +87,19 @@ impl DataExtractor for HeadersData {
 																bytes
 														}
 												});
+										} else if name == "expr_path" {
+												if headers_data.expr_path.is_some() {
+														slog::warn!(logger, "duplicate expr_path field");
+												} else {
+														headers_data.expr_path = Some(bytes);
+												}
 										}
+								} else if let Some(version) = field.trim().strip_prefix("version=") {
+										headers_data.version = version.parse().ok();
 								}
 						}
+				} else if name.eq_ignore_ascii_case("IC-CERTIFICATEEXPRESSION") {
+						headers_data.certificate_expression = Some(value.to_string());
 				} else if name.eq_ignore_ascii_case("CONTENT-ENCODING") {
 						let enc = value.trim().to_string();
 						headers_data.encoding = Some(enc);
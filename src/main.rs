@@ -1,6 +1,28 @@
-use crate::canister::resolve_canister_id_from_uri;
+use crate::canister::{failover_order, resolve_canister_id_from_uri};
+use crate::canister::{is_raw_host, resolve_canister_id_from_host};
 use crate::canister::PhoneBookCanisterParam;
-use crate::canister::{RealAccess, RedisParam};
+use crate::canister::{
+    AliasCache, CachedAccess, RealAccess, RedisParam, StaticAccess, NEGATIVE_ALIAS_VALUE,
+};
+use crate::asset_store::AssetStore;
+use crate::body_hash::DecompressionLimits;
+use crate::coalescing::{headers_key, Coalescer};
+use crate::conditional::{
+    body_etag, certified_etag, is_not_modified, not_modified_response, response_etag,
+};
+use crate::config::DnsAliasConfig;
+use crate::ic_error::{
+    agent_error_response, root_key_error_response, validation_error_response,
+    with_stream_verification, with_verification, IC_ERROR_HEADER,
+};
+use crate::range::{ByteRange, RangeRequest, RangeWindow};
+use crate::replica::{Replica, ReplicaPool};
+use crate::response_cache::{
+    from_redis_entry, redis_entry, redis_key, CacheHit, CachedResponse, ResponseCache,
+};
+use crate::req_validation::{
+    CertifiedRequest, CertifiedResponse, RawAccess, ValidationConfig, ValidationError, Verification,
+};
 use clap::{crate_authors, crate_version, Parser};
 use hyper::{
     body,
@@ -8,11 +30,7 @@ use hyper::{
     service::{make_service_fn, service_fn},
     Body, Request, Response, Server, StatusCode,
 };
-use ic_agent::{
-    agent::http_transport::ReqwestHttpReplicaV2Transport,
-    export::Principal,
-    Agent, AgentError,
-};
+use ic_agent::{export::Principal, AgentError};
 use ic_utils::{
     call::AsyncCall,
     call::SyncCall,
@@ -24,25 +42,33 @@ use ic_utils::{
 use redis::Commands;
 use slog::Drain;
 use std::{
+    collections::HashSet,
     convert::Infallible,
     error::Error,
     net::SocketAddr,
     path::PathBuf,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc, Mutex,
-    },
+    sync::Arc,
+    time::Duration,
 };
 use tokio::sync::mpsc;
 
 use crate::ic_req_headers::HeadersData;
 use crate::ic_req_headers::DataExtractor;
 
+mod asset_store;
+mod body_hash;
 mod canister;
-//mod config;
+mod coalescing;
+mod conditional;
+mod config;
+mod http_certification;
+mod ic_error;
 mod logging;
+mod range;
+mod replica;
 mod ic_req_headers;
 mod req_validation;
+mod response_cache;
 
 // The maximum length of a body we should log as tracing.
 const MAX_LOG_BODY_SIZE: usize = 100;
@@ -52,6 +78,18 @@ type HttpResponseAny = HttpResponse<Token, HttpRequestStreamingCallbackAny>;
 const MAX_HTTP_REQUEST_STREAM_CALLBACK_CALL_COUNT: i32 = 1000;
 //set str because clap need str for default value.
 const DEFAULT_REDIS_EXPIRY_CACHE_TIMEOUT_IN_SECOND: &'static str = "3600"; //24h = 3600 * 24
+const DEFAULT_ALIAS_CACHE_TIMEOUT_IN_SECOND: &'static str = "600";
+const DEFAULT_ALIAS_CACHE_SIZE: &'static str = "10000";
+const DEFAULT_NEGATIVE_CACHE_TIMEOUT_IN_SECOND: &'static str = "60";
+const DEFAULT_HEALTH_CHECK_INTERVAL_IN_SECOND: &'static str = "10";
+const DEFAULT_UNHEALTHY_THRESHOLD: &'static str = "3";
+const DEFAULT_MAX_REDIRECTS: &'static str = "5";
+const DEFAULT_MAX_CERT_TIME_OFFSET_IN_SECOND: &'static str = "300";
+const DEFAULT_MAX_DECOMPRESSION_RATIO: &'static str = "200";
+const DEFAULT_RESPONSE_CACHE_SIZE: &'static str = "67108864"; //64mb
+const DEFAULT_ASSET_STORE_SIZE: &'static str = "1073741824"; //1gb
+const DEFAULT_COALESCING_BUFFER_SIZE: &'static str = "8388608"; //8mb
+const DEFAULT_STALE_IF_ERROR_IN_SECOND: &'static str = "3600";
 
 #[derive(Parser)]
 #[clap(
@@ -89,6 +127,15 @@ pub(crate) struct Opts {
     #[clap(long, default_value = "http://localhost:8000/")]
     replica: Vec<String>,
 
+    /// Interval in seconds between two health checks of the replicas on their
+    /// `/api/v2/status` end point. 0 disables the health checks.
+    #[clap(long, default_value = DEFAULT_HEALTH_CHECK_INTERVAL_IN_SECOND)]
+    health_check_interval: u64,
+
+    /// Number of consecutive failed health checks before a replica stops receiving requests.
+    #[clap(long, default_value = DEFAULT_UNHEALTHY_THRESHOLD)]
+    unhealthy_threshold: usize,
+
     /// Whether or not this is run in a debug context (e.g. errors returned in responses
     /// should show full stack and error details).
     #[clap(long)]
@@ -99,44 +146,211 @@ pub(crate) struct Opts {
     #[clap(long)]
     fetch_root_key: bool,
 
-    /// A map of domain names to canister IDs.
-    /// Format: domain.name:canister-id
+    /// The url of the Redis server used to cache the alias to canister id resolution.
+    /// Without it, only the in-process alias cache is used.
     #[clap(long, short('r'))]
-    redis_url: String,
+    redis_url: Option<String>,
 
-    /// A map of domain names to canister IDs.
-    /// Format: domain.name:canister-id
+    /// The id of the phone book canister used to resolve an alias to a canister id.
+    /// Without it, only the canister ids and the static dns aliases can be used.
     #[clap(long, short('p'))]
-    phonebook_id: String,
+    phonebook_id: Option<String>,
 
     /// The address to bind to.
     #[clap(long, default_value = DEFAULT_REDIS_EXPIRY_CACHE_TIMEOUT_IN_SECOND)]
     redis_cache_timeout: usize,
+
+    /// Time to live in seconds of the in-process alias cache entries.
+    #[clap(long, default_value = DEFAULT_ALIAS_CACHE_TIMEOUT_IN_SECOND)]
+    alias_cache_timeout: u64,
+
+    /// Maximum number of aliases kept in the in-process cache. 0 disables the cache.
+    #[clap(long, default_value = DEFAULT_ALIAS_CACHE_SIZE)]
+    alias_cache_size: usize,
+
+    /// Time to live in seconds of the aliases unknown by the phone book canister,
+    /// in the in-process cache and in Redis.
+    #[clap(long, default_value = DEFAULT_NEGATIVE_CACHE_TIMEOUT_IN_SECOND)]
+    negative_cache_timeout: u64,
+
+    /// A base domain under which canisters are served by subdomain. With `nft.origyn.network`,
+    /// `<canister-id>.nft.origyn.network`, `<alias>.nft.origyn.network` and
+    /// `<canister-id>.raw.nft.origyn.network` are resolved from the Host header.
+    /// Can be passed multiple times.
+    #[clap(long)]
+    domain: Vec<String>,
+
+    /// A map of domain names or path aliases to canister IDs, resolved before Redis
+    /// and the phone book canister. Can be passed multiple times.
+    /// Format: domain.name:canister-id or alias:canister-id
+    #[clap(long)]
+    dns_alias: Vec<String>,
+
+    /// A file containing dns alias entries, one `name:canister-id` per line.
+    /// Empty lines and lines starting with `#` are ignored.
+    #[clap(long)]
+    dns_alias_file: Option<PathBuf>,
+
+    /// Follow the redirects returned by the canisters to a canister served by this proxy
+    /// and return the final response. Redirects to other sites are returned untouched.
+    #[clap(long)]
+    follow_redirects: bool,
+
+    /// Maximum number of redirects followed for one request with `--follow-redirects`.
+    #[clap(long, default_value = DEFAULT_MAX_REDIRECTS)]
+    max_redirects: usize,
+
+    /// Maximum difference in seconds between the time of a response certificate and
+    /// the local time. Older certificates and certificates from the future are rejected.
+    /// 0 disables the check.
+    #[clap(long, default_value = DEFAULT_MAX_CERT_TIME_OFFSET_IN_SECOND)]
+    max_cert_time_offset: u64,
+
+    /// What to do with the responses and the streaming chunks without certification:
+    /// "permissive" serves them, "warn" serves them and logs a warning, "strict" rejects them.
+    #[clap(long, default_value("permissive"), possible_values(&["permissive", "warn", "strict"]))]
+    certification_mode: String,
+
+    /// Certification mode of a canister, in the format `canister-id:mode`. Can be repeated.
+    #[clap(long)]
+    canister_certification_mode: Vec<String>,
+
+    /// Serve the responses that fail verification instead of rejecting them.
+    /// The failures are logged.
+    #[clap(long)]
+    skip_body_verification: bool,
+
+    /// Ignore the `_raw` query parameter and the raw hosts, all the responses are validated.
+    #[clap(long)]
+    disable_raw: bool,
+
+    /// Only these canisters can be called without validation with the `_raw` query
+    /// parameter or a raw host. Can be repeated.
+    #[clap(long)]
+    raw_allowlist: Vec<String>,
+
+    /// Maximum size in bytes of a compressed body once decoded for body verification.
+    /// 0 is no limit.
+    #[clap(long, default_value = "0")]
+    max_decompressed_size: u64,
+
+    /// Maximum ratio between the decoded and the compressed size of a body, larger ratios
+    /// are rejected as decompression bombs. 0 is no limit.
+    #[clap(long, default_value = DEFAULT_MAX_DECOMPRESSION_RATIO)]
+    max_decompression_ratio: u64,
+
+    /// Decompression limits of a canister, in the format `canister-id:max-size:max-ratio`.
+    /// An empty limit is the global one. Can be repeated.
+    #[clap(long)]
+    canister_decompression_limits: Vec<String>,
+
+    /// Maximum size in bytes of the in-process cache of the certified canister responses.
+    /// 0 disables the cache.
+    #[clap(long, default_value = DEFAULT_RESPONSE_CACHE_SIZE)]
+    response_cache_size: usize,
+
+    /// Maximum time in seconds after their expiration the cached responses are served
+    /// when the canister can't be reached. 0 disables it.
+    #[clap(long, default_value = DEFAULT_STALE_IF_ERROR_IN_SECOND)]
+    stale_if_error: u64,
+
+    /// A directory where the verified bodies of the streamed assets are stored, to serve
+    /// them from disk when the certified hash of the asset is unchanged.
+    #[clap(long)]
+    asset_store_dir: Option<PathBuf>,
+
+    /// Maximum size in bytes of the bodies kept in `--asset-store-dir`.
+    #[clap(long, default_value = DEFAULT_ASSET_STORE_SIZE)]
+    asset_store_size: u64,
+
+    /// Send each GET and HEAD request to the canister, without sharing the canister query
+    /// between the identical concurrent requests.
+    #[clap(long)]
+    disable_request_coalescing: bool,
+
+    /// Maximum size in bytes of the body buffered for the identical concurrent requests,
+    /// the next requests query the canister once it's larger.
+    #[clap(long, default_value = DEFAULT_COALESCING_BUFFER_SIZE)]
+    coalescing_buffer_size: usize,
 }
 
+// Forward the request to the canister. The identical concurrent GET and HEAD requests
+// share one canister query and one stream of the body.
+#[allow(clippy::too_many_arguments)]
 async fn forward_request(
     request: Request<Body>,
-    agent: Arc<Agent>,
+    replicas: &[Arc<Replica>],
+    redis_param: Option<&RedisParam>,
+    phonebook_param: Option<&PhoneBookCanisterParam>,
+    validation_config: &Arc<ValidationConfig>,
+    response_cache: &Arc<ResponseCache>,
+    asset_store: Option<&Arc<AssetStore>>,
+    coalescer: Option<&Arc<Coalescer>>,
+    logger: slog::Logger,
+    canister_params: TargetCanisterParams,
+) -> Result<Response<Body>, Box<dyn Error>> {
+    let key = coalescer.and_then(|_| coalescing_key(&request, &canister_params));
+    let forward = forward_request_to_canister(
+        request,
+        replicas,
+        redis_param,
+        phonebook_param,
+        validation_config,
+        response_cache,
+        asset_store,
+        logger,
+        canister_params,
+    );
+    match (coalescer, key) {
+        (Some(coalescer), Some(key)) => coalescer.coalesce(key, forward).await,
+        _ => forward.await,
+    }
+}
+
+// The key of the requests sharing a canister query: the GET and HEAD requests of the same
+// canister uri, with the same request headers sent to the canister. The requests with
+// credentials are not shared.
+fn coalescing_key(request: &Request<Body>, canister_params: &TargetCanisterParams) -> Option<String> {
+    let method = request.method();
+    if method != hyper::Method::GET && method != hyper::Method::HEAD {
+        return None;
+    }
+    let headers_key = headers_key(request.headers())?;
+    Some(format!(
+        "{} {:?} {} {}{}",
+        method,
+        canister_params.canister_ids,
+        canister_params.found_uri,
+        canister_params.raw || has_raw_parameter(request.uri()),
+        headers_key
+    ))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn forward_request_to_canister(
+    request: Request<Body>,
+    replicas: &[Arc<Replica>],
     redis_param: Option<&RedisParam>,
     phonebook_param: Option<&PhoneBookCanisterParam>,
+    validation_config: &Arc<ValidationConfig>,
+    response_cache: &Arc<ResponseCache>,
+    asset_store: Option<&Arc<AssetStore>>,
     logger: slog::Logger,
     canister_params: TargetCanisterParams,
 ) -> Result<Response<Body>, Box<dyn Error>> {
-    let ( canister_id, found_uri ) = match canister_params.clone() {
-        TargetCanisterParams { canister_id, found_uri } => (canister_id, found_uri)
+    let ( canister_ids, found_uri ) = match canister_params.clone() {
+        TargetCanisterParams { canister_ids, found_uri, .. } => (canister_ids, found_uri)
     };
-    let request_uri = request.uri();
 
     slog::trace!(
         logger,
-        "<< {} {} {:?} resolved to {}/-/{}",
+        "<< {} {} {:?} resolved to {:?}/-/{}",
         request.method(),
         request.uri(),
         &request.version(),
-        canister_id,
+        canister_ids,
         found_uri,
     );
-    let skip_validation = skip_validation(&request_uri);
 
     let (parts, body) = request.into_parts();
 
@@ -174,32 +388,128 @@ async fn forward_request(
         );
     }
 
-    let canister = HttpRequestCanister::create(agent.as_ref(), canister_id);
-    let query_result = canister
-        .http_request_custom(
-            method.as_str(),
-            found_uri.as_str(),
-            headers.iter().cloned(),
-            &entire_body,
-        )
-        .call()
-        .await;
+    //the certified responses to GET requests are served from the response cache,
+    //raw requests always query the canister.
+    let raw_request = canister_params.raw || has_raw_parameter(&parts.uri);
+    let cacheable = method == hyper::Method::GET && !raw_request;
+    let cache_hit = if cacheable {
+        canister_ids.iter().find_map(|canister_id| {
+            response_cache
+                .get(canister_id, &found_uri, &parts.headers)
+                .map(|hit| (*canister_id, hit))
+        })
+    } else {
+        None
+    };
+
+    //query the canisters of the alias in order until one answers. The query is idempotent
+    //so it's sent to the next replica when the replica can't be reached.
+    let mut replica_index = 0;
+    let mut canister_index = 0;
+    let (replica, canister_id, query_result) = loop {
+        if let Some((canister_id, hit)) = &cache_hit {
+            let http_response = HttpResponseAny {
+                status_code: hit.response.status_code,
+                headers: hit.response.header_fields(),
+                body: hit.response.body.clone(),
+                streaming_strategy: None,
+                upgrade: None,
+            };
+            break (replicas[0].clone(), *canister_id, Ok((http_response,)));
+        }
+        let replica = &replicas[replica_index];
+        let canister_id = canister_ids[canister_index];
+        if let Err(err) = replica.ensure_root_key().await {
+            slog::warn!(logger, "Replica {} root key fetch failed: {}", replica.url, err);
+            if replica_index + 1 < replicas.len() {
+                replica_index += 1;
+                continue;
+            }
+            if cacheable {
+                let stale = stale_response(
+                    &canister_ids,
+                    &found_uri,
+                    &parts.headers,
+                    response_cache,
+                    redis_param,
+                    &logger,
+                )
+                .await;
+                if let Some(response) = stale {
+                    return Ok(response);
+                }
+            }
+            return Ok(root_key_error_response(&err));
+        }
+        let query_result = HttpRequestCanister::create(&replica.agent, canister_id)
+            .http_request_custom(
+                method.as_str(),
+                found_uri.as_str(),
+                headers.iter().cloned(),
+                &entire_body,
+            )
+            .call()
+            .await;
+        match &query_result {
+            Err(err) if replica_index + 1 < replicas.len() && is_replica_failover_error(err) => {
+                slog::warn!(
+                    logger,
+                    "Replica {} failed, retry with the next replica: {}",
+                    replica.url,
+                    err
+                );
+                replica_index += 1;
+            }
+            Err(err)
+                if canister_index + 1 < canister_ids.len() && is_canister_failover_error(err) =>
+            {
+                slog::warn!(
+                    logger,
+                    "Canister {} failed, retry with the next canister of the alias: {}",
+                    canister_id,
+                    err
+                );
+                canister_index += 1;
+            }
+            _ => break (replica.clone(), canister_id, query_result),
+        }
+    };
+    //the last verified response is served when the replicas can't be reached.
+    if let Err(err) = &query_result {
+        if cacheable && is_stale_if_error(err) {
+            let stale = stale_response(
+                &canister_ids,
+                &found_uri,
+                &parts.headers,
+                response_cache,
+                redis_param,
+                &logger,
+            )
+            .await;
+            if let Some(response) = stale {
+                return Ok(response);
+            }
+        }
+    }
+    let agent = &replica.agent;
+    let canister = HttpRequestCanister::create(agent, canister_id);
+    //the validation is skipped with a raw host or the `_raw` query parameter,
+    //when the raw access is allowed for the canister.
+    let skip_validation = raw_request && validation_config.allows_raw(&canister_id);
+    let certified_request = CertifiedRequest {
+        method: method.as_str(),
+        url: &found_uri,
+        headers: &headers,
+        body: &entire_body,
+    };
 
     fn handle_result(
         result: Result<(HttpResponseAny,), AgentError>,
     ) -> Result<HttpResponseAny, Result<Response<Body>, Box<dyn Error>>> {
-        // If the result is a Replica error, returns the 500 code and message. There is no information
-        // leak here because a user could use `dfx` to get the same reply.
+        // Agent errors are mapped to an http status and an error code, see `ic_error`.
         match result {
             Ok((http_response,)) => Ok(http_response),
-            Err(AgentError::ReplicaError {
-                reject_code,
-                reject_message,
-            }) => Err(Ok(Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(format!(r#"Replica Error ({}): "{}""#, reject_code, reject_message).into())
-                .unwrap())),
-            Err(e) => Err(Err(e.into())), // <-- PLACE TO HANDLE 307 REDIRECT WITH RECURSION>
+            Err(e) => Err(Ok(agent_error_response(&e))),
         }
     }
 
@@ -208,7 +518,9 @@ async fn forward_request(
         Err(response_or_error) => return response_or_error,
     };
 
-    let http_response = if http_response.upgrade == Some(true) {
+    //the responses of update calls are not cached.
+    let upgraded = http_response.upgrade == Some(true);
+    let http_response = if upgraded {
         let waiter = garcon::Delay::builder()
             .throttle(std::time::Duration::from_millis(500))
             .timeout(std::time::Duration::from_secs(15))
@@ -231,10 +543,16 @@ async fn forward_request(
         http_response
     };
 
-    let mut builder = Response::builder().status(StatusCode::from_u16(http_response.status_code)?);
-    for HeaderField(name, value) in &http_response.headers {
-        builder = builder.header(name.as_ref(), value.as_ref());
-    }
+    //a `Range` is applied to the complete responses of the canister with a known length,
+    //the length of a streamed response is its `Content-Length`. The range is evaluated
+    //after the conditional headers, with the entity tag of the response.
+    let length = if http_response.streaming_strategy.is_some() {
+        range::content_length(&http_response.headers)
+    } else {
+        Some(http_response.body.len() as u64)
+    };
+    let status = StatusCode::from_u16(http_response.status_code)?;
+    let age = cache_hit.as_ref().map(|(_, hit)| hit.age);
 
     let headers_data: HeadersData = HeadersData::extract(&http_response.headers, &logger);
     let body = if logger.is_trace_enabled() {
@@ -253,137 +571,435 @@ async fn forward_request(
     }
     let response = if let Some(streaming_strategy) = http_response.streaming_strategy {
         let (mut sender, body) = body::Body::channel();
-        let agent = agent.as_ref().clone();
-        sender.send_data(Bytes::from(http_response.body.clone())).await?;
+        let agent = agent.clone();
 
+        //the first chunk is certified alone when each chunk is certified by its token,
+        //otherwise the certified hash is the hash of the whole body, checked at the end
+        //of the stream.
+        let mut body_hash = None;
+        let mut verification = Verification::Skipped;
         if !skip_validation {
-            //verification first chunk
-            let body_valid = req_validation::validate(
+            let first_body = &http_response.body;
+            let certified_body = req_validation::certified_body(
                 &headers_data,
                 &canister_id,
                 &agent,
-                &parts.uri,
-                &http_response.body.clone(),
-                logger.clone(),
+                &certified_request,
+                &CertifiedResponse {
+                    status_code: http_response.status_code,
+                    headers: &http_response.headers,
+                },
+                validation_config,
+                &logger,
+            )
+            .and_then(|certified_body| match certified_body {
+                Some(certified_body) if certified_body.check(first_body).is_err() => {
+                    let mut hasher = certified_body.hasher()?;
+                    hasher
+                        .update(first_body)
+                        .map_err(ValidationError::Body)?;
+                    Ok((certified_body.verification(), Some((certified_body, hasher))))
+                }
+                Some(certified_body) => Ok((certified_body.verification(), None)),
+                None => Ok((Verification::Uncertified, None)),
+            });
+            let (first_verification, first_body_hash) = match certified_body {
+                Ok(certified_body) => certified_body,
+                Err(err) => {
+                    match req_validation::skip_verification_failure(Err(err), validation_config, &logger) {
+                        Ok(verification) => (verification, None),
+                        Err(err) => {
+                            if matches!(err, ValidationError::Certificate(_)) {
+                                replica.invalidate_root_key();
+                            }
+                            return Ok(validation_error_response(&err));
+                        }
+                    }
+                }
+            };
+            verification = first_verification;
+            body_hash = first_body_hash;
+        }
+
+        //the certified hash of the whole body is known before the body is streamed with the
+        //v1 certification, it's the entity tag of the response when the canister has none.
+        let status_code = http_response.status_code;
+        let canister_etag = response_etag(&http_response.headers).map(str::to_string);
+        let etag = canister_etag.clone().or_else(|| {
+            body_hash
+                .as_ref()
+                .and_then(|(certified_body, _)| certified_body.decoded_sha())
+                .filter(|_| status_code == 200)
+                .and_then(|(sha, encoding)| certified_etag(sha, encoding))
+        });
+        if is_conditional(&method, status_code, &verification)
+            && is_not_modified(&parts.headers, &http_response.headers, etag.as_deref())
+        {
+            return Ok(
+                with_verification(not_modified_response(&http_response.headers, etag.as_deref()), &verification)
+                    .body(Body::empty())?,
             );
-            if body_valid.is_err() {
-                return Ok(Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(body_valid.unwrap_err().into())
-                    .unwrap());
-            }
+        }
+        let range = match response_range(&parts.headers, &http_response.headers, status, length, etag.as_deref()) {
+            Ok(range) => range,
+            Err(length) => return range_not_satisfiable(length),
+        };
+        let mut builder = response_builder(status, &http_response.headers, length, range, age);
+        if let (None, Some(etag)) = (&canister_etag, &etag) {
+            builder = builder.header(hyper::header::ETAG, etag);
         }
 
-        slog::info!(
-            logger,
-            "==[ STREAMING ]==> FIRST CHUNK: {:?}",
-            http_response.headers.clone(),
-        );
+        //only the bytes of the range are sent, the chunks are still validated in full.
+        let mut window = range.map(|(range, _)| RangeWindow::new(range));
+        let chunk_size = http_response.body.len() as u64;
+        let first_chunk = match window.as_mut() {
+            Some(window) => window.trim(&http_response.body),
+            None => Bytes::from(http_response.body.clone()),
+        };
 
-        match streaming_strategy {
-            StreamingStrategy::Callback(callback) => {
-                // let { principal, method } = callback.callback.0;
-                slog::info!(
-                    logger,
-                    "==[ STREAMING ]==> StreamingStrategy::CALLBACK: {:?}",
-                    callback.clone(),
-                );
+        //a whole body already verified is read from the asset store instead of streaming
+        //the chunks from the canister. The stored body has the `Content-Length` of the response.
+        let asset_key = body_hash
+            .as_ref()
+            .and_then(|(certified_body, _)| certified_body.decoded_sha())
+            .filter(|_| status_code == 200)
+            .zip(length);
+        let stored_asset = match (asset_store, asset_key) {
+            (Some(asset_store), Some(((sha, encoding), size))) => match asset_store.get(sha, encoding, size) {
+                Some(path) => tokio::fs::File::open(path).await.ok(),
+                None => None,
+            },
+            _ => None,
+        };
+        //a certified stream is aborted when a later chunk fails verification, it's only
+        //partially certified when a later chunk can be served without certification.
+        let certified = matches!(verification, Verification::CertifiedV1 | Verification::CertifiedV2);
+        let partial = certified
+            && stored_asset.is_none()
+            && !validation_config.certifies_whole_stream(&canister_id, body_hash.is_some());
+        if let Some(file) = stored_asset {
+            let window = range.map(|(range, _)| RangeWindow::new(range));
+            let logger = logger.clone();
+            tokio::spawn(async move {
+                if let Err(err) = asset_store::send_file(file, window, &mut sender).await {
+                    slog::debug!(logger, "Error stored body: {}", err);
+                    sender.abort();
+                }
+            });
+        } else {
+            //the verified body is added to the asset store at the end of the stream.
+            let mut asset_writer = None;
+            if let (Some(asset_store), Some(((sha, encoding), size))) = (asset_store, asset_key) {
+                match asset_store.writer(sha, encoding, size).await {
+                    Ok(mut writer) => match writer.write(&http_response.body).await {
+                        Ok(()) => asset_writer = Some(writer),
+                        Err(err) => slog::debug!(logger, "Error asset store: {}", err),
+                    },
+                    Err(err) => slog::debug!(logger, "Error asset store: {}", err),
+                }
+            }
+
+            //with the hash of the whole body, the last bytes are held until the hash is checked.
+            let mut pending = None;
+            if body_hash.is_some() {
+                pending = Some(first_chunk);
+            } else {
+                sender.send_data(first_chunk).await?;
+            }
+
+            slog::info!(
+                logger,
+                "==[ STREAMING ]==> FIRST CHUNK: {:?}",
+                http_response.headers.clone(),
+            );
+
+            match streaming_strategy {
+                StreamingStrategy::Callback(callback) => {
+                    // let { principal, method } = callback.callback.0;
+                    slog::info!(
+                        logger,
+                        "==[ STREAMING ]==> StreamingStrategy::CALLBACK: {:?}",
+                        callback.clone(),
+                    );
         
-                let streaming_canister_id = callback.callback.0.principal;
-                let method_name = callback.callback.0.method;
-                let mut callback_token = callback.token;
-                let logger = logger.clone();
-                let uri = parts.uri.clone();
+                    let streaming_canister_id = callback.callback.0.principal;
+                    let method_name = callback.callback.0.method;
+                    let mut callback_token = callback.token;
+                    //all the chunks are needed for the hash of the whole body. The token of
+                    //the skipped chunks is kept to fetch them if the chunks have other sizes.
+                    let mut skipped_token = None;
+                    if let (Some(window), None, Some(length)) = (window.as_mut(), body_hash.as_ref(), length) {
+                        let token = window.skip_chunks(callback_token.clone(), chunk_size, length);
+                        skipped_token = Some(std::mem::replace(&mut callback_token, token));
+                    }
+                    let logger = logger.clone();
+                    let url = found_uri.clone();
+                    let replica = replica.clone();
+                    let validation_config = validation_config.clone();
+                    //the verification of the last chunk validated.
+                    let mut chunk_verification = verification.clone();
 
-                slog::info!(
-                    logger,
-                    "==[ STREAMING ]==> PROCESSING CHUNK WITH CALLBACK_TOKEN: {:?}",
-                    callback_token.clone(),
-                );
+                    slog::info!(
+                        logger,
+                        "==[ STREAMING ]==> PROCESSING CHUNK WITH CALLBACK_TOKEN: {:?}",
+                        callback_token.clone(),
+                    );
 
-                tokio::spawn(async move {
-                    let canister = HttpRequestCanister::create(&agent, streaming_canister_id);
-                    // We have not yet called http_request_stream_callback.
-                    let mut count = 0;
-                    loop {
-                        count += 1;
-                        if count > MAX_HTTP_REQUEST_STREAM_CALLBACK_CALL_COUNT {
-                            sender.abort();
-                            break;
-                        }
+                    tokio::spawn(async move {
+                        let canister = HttpRequestCanister::create(&agent, streaming_canister_id);
+                        // We have not yet called http_request_stream_callback.
+                        let mut count = 0;
+                        loop {
+                            if body_hash.is_none() && window.as_ref().map_or(false, RangeWindow::is_done) {
+                                break;
+                            }
+                            count += 1;
+                            if count > MAX_HTTP_REQUEST_STREAM_CALLBACK_CALL_COUNT {
+                                sender.abort();
+                                break;
+                            }
 
-                        match canister
-                            .http_request_stream_callback(&method_name, callback_token)
-                            .call()
-                            .await
-                        {
-                            Ok((StreamingCallbackHttpResponse { body, token },)) => {
-                                slog::info!(
-                                    logger,
-                                    "==[ STREAMING_SPAWN ]==> REQUESTED CHUNK: {:?}",
-                                    body.len(),
-                                );
-                                slog::info!(
-                                    logger,
-                                    "==[ STREAMING_SPAWN ]==> REQUESTED CHUNK TOKEN: {:?}",
-                                    token.clone(),
-                                );
-
-                                if !skip_validation  {
-                                    let is_chunk_valid = req_validation::validate_chunk(
-                                        StreamingCallbackHttpResponse { body: body.clone(), token: token.clone() },
-                                        canister_id.clone(),
-                                        &agent,
-                                        &parts.uri,
-                                        logger.clone(),
+                            match canister
+                                .http_request_stream_callback(&method_name, callback_token)
+                                .call()
+                                .await
+                            {
+                                Ok((StreamingCallbackHttpResponse { body, token },)) => {
+                                    slog::info!(
+                                        logger,
+                                        "==[ STREAMING_SPAWN ]==> REQUESTED CHUNK: {:?}",
+                                        body.len(),
+                                    );
+                                    slog::info!(
+                                        logger,
+                                        "==[ STREAMING_SPAWN ]==> REQUESTED CHUNK TOKEN: {:?}",
+                                        token.clone(),
                                     );
-                                    if is_chunk_valid.is_err() {
-                                        slog::debug!(logger, "Error chunk_body_valid is not valid");
-                                        sender.abort();
+
+                                    if let Some((_, hasher)) = body_hash.as_mut() {
+                                        if let Err(err) = hasher.update(&body) {
+                                            slog::debug!(logger, "Error streamed body: {}", err);
+                                            sender.abort();
+                                            break;
+                                        }
+                                        if let Some(writer) = asset_writer.as_mut() {
+                                            if let Err(err) = writer.write(&body).await {
+                                                slog::debug!(logger, "Error asset store: {}", err);
+                                                asset_writer = None;
+                                            }
+                                        }
+                                    } else if !skip_validation  {
+                                        let is_chunk_valid = req_validation::validate_chunk(
+                                            StreamingCallbackHttpResponse { body: body.clone(), token: token.clone() },
+                                            canister_id.clone(),
+                                            &agent,
+                                            &url,
+                                            &chunk_verification,
+                                            &validation_config,
+                                            logger.clone(),
+                                        )
+                                        .and_then(|verification| match verification {
+                                            Verification::Failed(err) if certified => Err(err),
+                                            verification => Ok(verification),
+                                        });
+                                        match is_chunk_valid {
+                                            Ok(verification) => chunk_verification = verification,
+                                            Err(err) => {
+                                                slog::debug!(logger, "Error chunk_body_valid is not valid: {}", err);
+                                                if matches!(err, ValidationError::Certificate(_)) {
+                                                    replica.invalidate_root_key();
+                                                }
+                                                sender.abort();
+                                                break;
+                                            }
+                                        }
+                                    }
+
+                                    if let Some(window) = window.as_mut() {
+                                        if !window.check_chunk(body.len(), token.is_none()) {
+                                            match skipped_token.take() {
+                                                Some(skipped_token) if window.restart() => {
+                                                    callback_token = skipped_token;
+                                                    continue;
+                                                }
+                                                _ => {
+                                                    slog::debug!(logger, "Error streamed chunk is not at the position of the range");
+                                                    sender.abort();
+                                                    break;
+                                                }
+                                            }
+                                        }
+                                    }
+                                    let data = match window.as_mut() {
+                                        Some(window) => window.trim(&body),
+                                        None => Bytes::from(body),
+                                    };
+                                    let data = if data.is_empty() {
+                                        None
+                                    } else if body_hash.is_some() {
+                                        pending.replace(data)
+                                    } else {
+                                        Some(data)
+                                    };
+                                    if let Some(data) = data {
+                                        if sender.send_data(data).await.is_err() {
+                                            sender.abort();
+                                            break;
+                                        }
+                                    }
+                                    if let Some(next_token) = token {
+                                        callback_token = next_token;
+                                    } else {
+                                        //the stream ended before the end of the range.
+                                        if !window.as_ref().map_or(true, RangeWindow::is_done) {
+                                            sender.abort();
+                                            break;
+                                        }
+                                        if let Some((certified_body, hasher)) = body_hash.take() {
+                                            let body_valid = hasher
+                                                .finish()
+                                                .map_err(ValidationError::Body)
+                                                .and_then(|body_sha| certified_body.check_sha(&body_sha))
+                                                .map(|()| certified_body.verification());
+                                            if body_valid.is_err() {
+                                                asset_writer = None;
+                                            }
+                                            //the failure of a certified stream can't be served.
+                                            let body_valid = if certified {
+                                                body_valid
+                                            } else {
+                                                req_validation::skip_verification_failure(
+                                                    body_valid,
+                                                    &validation_config,
+                                                    &logger,
+                                                )
+                                            };
+                                            if let Err(err) = body_valid {
+                                                slog::debug!(logger, "Error streamed body is not valid: {}", err);
+                                                sender.abort();
+                                                break;
+                                            }
+                                        }
+                                        if let Some(data) = pending.take() {
+                                            if sender.send_data(data).await.is_err() {
+                                                sender.abort();
+                                            }
+                                        }
+                                        if let Some(writer) = asset_writer.take() {
+                                            if let Err(err) = writer.commit().await {
+                                                slog::debug!(logger, "Error asset store: {}", err);
+                                            }
+                                        }
                                         break;
                                     }
                                 }
-                               
-                                if sender.send_data(Bytes::from(body)).await.is_err() {
+                                Err(e) => {
+                                    slog::debug!(logger, "Error happened during streaming: {}", e);
                                     sender.abort();
                                     break;
                                 }
-                                if let Some(next_token) = token {
-                                    callback_token = next_token;
-                                } else {
-                                    break;
-                                }
-                            }
-                            Err(e) => {
-                                slog::debug!(logger, "Error happened during streaming: {}", e);
-                                sender.abort();
-                                break;
                             }
                         }
-                    }
-                });
+                    });
+                }
             }
-        }
 
-        builder.body(body)?
+        }
+        with_stream_verification(builder, &verification, partial).body(body)?
     } else {
-        if !skip_validation {
-            let body_valid = req_validation::validate(
+        let verification = if let Some((_, hit)) = &cache_hit {
+            hit.response.verification.clone()
+        } else if skip_validation {
+            Verification::Skipped
+        } else {
+            let body_valid = req_validation::validate_response(
                 &headers_data,
                 &canister_id,
                 &agent,
-                &parts.uri,
+                &certified_request,
+                &CertifiedResponse {
+                    status_code: http_response.status_code,
+                    headers: &http_response.headers,
+                },
                 &http_response.body,
+                validation_config,
                 logger.clone(),
             );
-            if body_valid.is_err() {
-                return Ok(Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(body_valid.unwrap_err().into())
-                    .unwrap());
+            match body_valid {
+                Ok(verification) => verification,
+                Err(err) => {
+                    if matches!(err, ValidationError::Certificate(_)) {
+                        replica.invalidate_root_key();
+                    }
+                    return Ok(validation_error_response(&err));
+                }
+            }
+        };
+        //the complete response is cached, a range is applied to the cached response.
+        if cacheable && cache_hit.is_none() && !upgraded && http_response.status_code == 200 {
+            let cached = CachedResponse::new(
+                http_response.status_code,
+                &http_response.headers,
+                &http_response.body,
+                verification.clone(),
+            );
+            //a copy is kept in Redis, to serve it when the canister can't be reached.
+            let max_stale = response_cache.max_stale();
+            if let (Some(redis_param), false) = (redis_param, max_stale.is_zero()) {
+                if let Some((key, value, ttl)) =
+                    redis_entry(&canister_id, &found_uri, &parts.headers, &cached, max_stale)
+                {
+                    let redis_param = redis_param.clone();
+                    let logger = logger.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = redis_param.set_bytes(&key, &value, ttl).await {
+                            slog::error!(logger, "Error during Redis response cache update: {}", err);
+                        }
+                    });
+                }
             }
+            response_cache.insert(&canister_id, &found_uri, &parts.headers, cached);
+        }
+
+        //the entity tag of a certified body without `ETag` is the sha256 of the decoded body,
+        //the same tag as a streamed body.
+        let canister_etag = response_etag(&http_response.headers).map(str::to_string);
+        let etag = canister_etag.clone().or_else(|| {
+            if is_conditional(&method, http_response.status_code, &verification) {
+                body_etag(
+                    &http_response.body,
+                    headers_data.encoding.as_deref(),
+                    validation_config.decompression_limits(&canister_id),
+                )
+            } else {
+                None
+            }
+        });
+        if is_conditional(&method, http_response.status_code, &verification)
+            && is_not_modified(&parts.headers, &http_response.headers, etag.as_deref())
+        {
+            return Ok(
+                with_verification(not_modified_response(&http_response.headers, etag.as_deref()), &verification)
+                    .body(Body::empty())?,
+            );
+        }
+        let range = match response_range(&parts.headers, &http_response.headers, status, length, etag.as_deref()) {
+            Ok(range) => range,
+            Err(length) => return range_not_satisfiable(length),
+        };
+        let mut builder = response_builder(status, &http_response.headers, length, range, age);
+        if let (None, Some(etag)) = (&canister_etag, &etag) {
+            builder = builder.header(hyper::header::ETAG, etag);
+        }
+        let builder = with_verification(builder, &verification);
+        match range {
+            Some((range, _)) => builder.body(
+                http_response.body[range.start as usize..=range.end as usize]
+                    .to_vec()
+                    .into(),
+            )?,
+            None => builder.body(http_response.body.into())?,
         }
-        builder.body(http_response.body.into())?
     };
 
     if logger.is_trace_enabled() {
@@ -421,9 +1037,146 @@ async fn forward_request(
     Ok(response)
 }
 
-fn skip_validation(url: &hyper::Uri) -> bool {
+//the replica could not be reached or answered with a server error.
+fn is_replica_failover_error(err: &AgentError) -> bool {
+    match err {
+        AgentError::TransportError(_) => true,
+        AgentError::HttpError(payload) => payload.status >= 500,
+        _ => false,
+    }
+}
+
+//a reject or a transport error can be specific to one canister of an alias.
+fn is_canister_failover_error(err: &AgentError) -> bool {
+    matches!(
+        err,
+        AgentError::ReplicaError { .. } | AgentError::TransportError(_)
+    )
+}
+
+//the replicas can't be reached or didn't answer in time.
+fn is_stale_if_error(err: &AgentError) -> bool {
+    is_replica_failover_error(err) || matches!(err, AgentError::TimeoutWaitingForResponse())
+}
+
+// The last verified response to a request, served when the canister can't be reached: a
+// cached response expired for less than `--stale-if-error`, from the response cache or
+// from Redis.
+async fn stale_response(
+    canister_ids: &[Principal],
+    url: &str,
+    request_headers: &hyper::HeaderMap,
+    response_cache: &ResponseCache,
+    redis_param: Option<&RedisParam>,
+    logger: &slog::Logger,
+) -> Option<Response<Body>> {
+    let max_stale = response_cache.max_stale();
+    if max_stale.is_zero() {
+        return None;
+    }
+    let mut hit = canister_ids
+        .iter()
+        .find_map(|canister_id| response_cache.get_stale(canister_id, url, request_headers));
+    if let (None, Some(redis_param)) = (&hit, redis_param) {
+        for canister_id in canister_ids {
+            match redis_param
+                .get_bytes(&redis_key(canister_id, url, request_headers))
+                .await
+            {
+                Ok(Some(value)) => hit = from_redis_entry(&value, request_headers, max_stale),
+                Ok(None) => {}
+                Err(err) => slog::warn!(logger, "Error during Redis response cache read: {}", err),
+            }
+            if hit.is_some() {
+                break;
+            }
+        }
+    }
+    let CacheHit { response, age } = hit?;
+    slog::warn!(logger, "Canister unreachable, serve a stale response of {}", url);
+
+    let mut builder = Response::builder().status(StatusCode::from_u16(response.status_code).ok()?);
+    for (name, value) in &response.headers {
+        builder = builder.header(name.as_str(), value.as_str());
+    }
+    with_verification(builder, &response.verification)
+        .header(hyper::header::AGE, age.as_secs())
+        .header(hyper::header::WARNING, "110 - \"Response is Stale\"")
+        .body(response.body.clone().into())
+        .ok()
+}
+
+//the certified responses to GET and HEAD requests can be answered with a `304 Not Modified`.
+fn is_conditional(method: &hyper::Method, status_code: u16, verification: &Verification) -> bool {
+    (method == hyper::Method::GET || method == hyper::Method::HEAD)
+        && status_code == 200
+        && matches!(verification, Verification::CertifiedV1 | Verification::CertifiedV2)
+}
+
+// The range of the response to a `Range` request, `Err` with the length of the body when
+// the range is not satisfiable. Only the complete `200` responses with a known length have
+// ranges.
+fn response_range(
+    request_headers: &hyper::HeaderMap,
+    response_headers: &[HeaderField],
+    status: StatusCode,
+    length: Option<u64>,
+    etag: Option<&str>,
+) -> Result<Option<(ByteRange, u64)>, u64> {
+    let length = match length {
+        Some(length) if status == StatusCode::OK => length,
+        _ => return Ok(None),
+    };
+    match range::requested_range(request_headers, response_headers, etag, length) {
+        Some(RangeRequest::Satisfiable(range)) => Ok(Some((range, length))),
+        Some(RangeRequest::Unsatisfiable) => Err(length),
+        None => Ok(None),
+    }
+}
+
+// The builder of the response with the headers of the canister response, and the headers
+// of the range when the response is partial.
+fn response_builder(
+    status: StatusCode,
+    response_headers: &[HeaderField],
+    length: Option<u64>,
+    range: Option<(ByteRange, u64)>,
+    age: Option<Duration>,
+) -> hyper::http::response::Builder {
+    let mut builder = Response::builder().status(status);
+    let mut accept_ranges = false;
+    for HeaderField(name, value) in response_headers {
+        if range.is_some() && name.eq_ignore_ascii_case(hyper::header::CONTENT_LENGTH.as_str()) {
+            continue;
+        }
+        accept_ranges |= name.eq_ignore_ascii_case(hyper::header::ACCEPT_RANGES.as_str());
+        builder = builder.header(name.as_ref(), value.as_ref());
+    }
+    if length.is_some() && status == StatusCode::OK && !accept_ranges {
+        builder = builder.header(hyper::header::ACCEPT_RANGES, "bytes");
+    }
+    if let Some(age) = age {
+        builder = builder.header(hyper::header::AGE, age.as_secs());
+    }
+    if let Some((range, length)) = range {
+        builder = builder
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(hyper::header::CONTENT_RANGE, range.content_range(length))
+            .header(hyper::header::CONTENT_LENGTH, range.len());
+    }
+    builder
+}
+
+fn range_not_satisfiable(length: u64) -> Result<Response<Body>, Box<dyn Error>> {
+    Ok(Response::builder()
+        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+        .header(hyper::header::CONTENT_RANGE, format!("bytes */{}", length))
+        .body(Body::empty())?)
+}
+
+fn has_raw_parameter(url: &hyper::Uri) -> bool {
     url.query()
-        .map(|query| if query.contains("_raw") { true } else { false })
+        .map(|query| url::form_urlencoded::parse(query.as_bytes()).any(|(name, _)| name == "_raw"))
         .unwrap_or(false)
 }
 
@@ -433,26 +1186,29 @@ fn ok() -> Result<Response<Body>, Box<dyn Error>> {
         .body("OK".into())?)
 }
 
-fn unable_to_fetch_root_key() -> Result<Response<Body>, Box<dyn Error>> {
-    Ok(Response::builder()
-        .status(StatusCode::INTERNAL_SERVER_ERROR)
-        .body("Unable to fetch root key".into())?)
-}
-
 #[derive(Clone, Debug)]
 pub struct TargetCanisterParams {
-    canister_id: Principal,
+    //the canisters to try in order, never empty.
+    canister_ids: Vec<Principal>,
     found_uri:  String,
+    //the request came through a `<name>.raw.<domain>` host.
+    raw: bool,
 }
 
 #[allow(clippy::too_many_arguments)]
 async fn handle_request(
     request: Request<Body>,
-    replica_url: String,
+    replicas: Arc<ReplicaPool>,
     redis_param: Arc<Option<RedisParam>>,
-    phonebook_param: Option<PhoneBookCanisterParam>,
+    domains: Arc<Vec<String>>,
+    dns_aliases: Arc<DnsAliasConfig>,
+    alias_cache: Arc<AliasCache>,
+    validation_config: Arc<ValidationConfig>,
+    response_cache: Arc<ResponseCache>,
+    asset_store: Option<Arc<AssetStore>>,
+    coalescer: Option<Arc<Coalescer>>,
+    max_redirects: usize,
     logger: slog::Logger,
-    fetch_root_key: bool,
     debug: bool,
 ) -> Result<Response<Body>, Infallible> {
     let request_uri = request.uri();
@@ -460,42 +1216,71 @@ async fn handle_request(
     let result = if request_uri.path().starts_with("/healthcheck") {
         ok()
     } else {
-        let agent = Arc::new(
-            ic_agent::Agent::builder()
-                .with_transport(ReqwestHttpReplicaV2Transport::create(replica_url).unwrap())
-                .build()
-                .expect("Could not create agent..."),
-        );
-        if fetch_root_key && agent.fetch_root_key().await.is_err() {
-            unable_to_fetch_root_key()
-        } else {
-            let request_uri = request.uri();
-            slog::trace!(logger, "Request URI: {:?}", request_uri.clone());
-            let (canister_id, found_uri) = match resolve_canister_id_from_uri(
-                &request_uri,
+        //select the replicas for this request.
+        let replicas = replicas.select();
+        let phonebook_param = replicas[0].phonebook_param.as_ref();
+        let request_uri = request.uri();
+        slog::trace!(logger, "Request URI: {:?}", request_uri.clone());
+        let host = request
+            .headers()
+            .get(hyper::header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| request_uri.host())
+            .unwrap_or_default();
+        let target = match resolve_target(
+            host,
+            request_uri,
+            false,
+            &domains,
+            redis_param.as_ref().as_ref(),
+            phonebook_param,
+            &dns_aliases,
+            &alias_cache,
+            &logger,
+        )
+        .await
+        {
+            None => {
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body("Could not find a canister id to forward to.".into())
+                    .unwrap())
+            }
+            Some(target) => target,
+        };
+
+        if max_redirects > 0 {
+            let host = host.to_string();
+            forward_request_following_redirects(
+                request,
+                host,
+                target,
+                max_redirects,
+                &replicas,
+                &domains,
                 redis_param.as_ref().as_ref(),
-                phonebook_param.as_ref(),
-                RealAccess,
+                phonebook_param,
+                &dns_aliases,
+                &alias_cache,
+                &validation_config,
+                &response_cache,
+                asset_store.as_ref(),
+                coalescer.as_ref(),
                 &logger,
             )
             .await
-            {
-                None => {
-                    return Ok(Response::builder()
-                        .status(StatusCode::BAD_REQUEST)
-                        .body("Could not find a canister id to forward to.".into())
-                        .unwrap())
-                }
-                Some((x, y)) => (x, y),
-            };
-        
+        } else {
             forward_request(
                 request,
-                agent,
+                &replicas,
                 redis_param.as_ref().as_ref(),
-                phonebook_param.as_ref(),
+                phonebook_param,
+                &validation_config,
+                &response_cache,
+                asset_store.as_ref(),
+                coalescer.as_ref(),
                 logger.clone(),
-                TargetCanisterParams { canister_id, found_uri },
+                target,
             )
             .await
         }
@@ -518,10 +1303,181 @@ async fn handle_request(
     }
 }
 
+// Resolve the canisters and the canister uri of a request. The Host header has priority
+// over the `/-/<alias>/-/` path scheme, which is not used when `host_only` is set.
+#[allow(clippy::too_many_arguments)]
+async fn resolve_target(
+    host: &str,
+    uri: &hyper::Uri,
+    host_only: bool,
+    domains: &[String],
+    redis_param: Option<&RedisParam>,
+    phonebook_param: Option<&PhoneBookCanisterParam>,
+    dns_aliases: &Arc<DnsAliasConfig>,
+    alias_cache: &Arc<AliasCache>,
+    logger: &slog::Logger,
+) -> Option<TargetCanisterParams> {
+    let resolver = StaticAccess::new(
+        dns_aliases.clone(),
+        CachedAccess::new(alias_cache.clone(), RealAccess),
+    );
+    let resolved = match resolve_canister_id_from_host(
+        host,
+        uri,
+        domains,
+        redis_param,
+        phonebook_param,
+        resolver.clone(),
+        logger,
+    )
+    .await
+    {
+        Some(resolved) => Some(resolved),
+        None if host_only => None,
+        None => {
+            resolve_canister_id_from_uri(uri, redis_param, phonebook_param, resolver, logger)
+                .await
+        }
+    };
+    resolved.map(|(canister_ids, found_uri)| TargetCanisterParams {
+        canister_ids: failover_order(canister_ids),
+        found_uri,
+        raw: is_raw_host(host, domains),
+    })
+}
+
+// Forward the request and follow the redirects to canisters served by this proxy,
+// up to `max_redirects` times. Redirects to other sites are returned to the client.
+#[allow(clippy::too_many_arguments)]
+async fn forward_request_following_redirects(
+    request: Request<Body>,
+    mut host: String,
+    mut target: TargetCanisterParams,
+    max_redirects: usize,
+    replicas: &[Arc<Replica>],
+    domains: &[String],
+    redis_param: Option<&RedisParam>,
+    phonebook_param: Option<&PhoneBookCanisterParam>,
+    dns_aliases: &Arc<DnsAliasConfig>,
+    alias_cache: &Arc<AliasCache>,
+    validation_config: &Arc<ValidationConfig>,
+    response_cache: &Arc<ResponseCache>,
+    asset_store: Option<&Arc<AssetStore>>,
+    coalescer: Option<&Arc<Coalescer>>,
+    logger: &slog::Logger,
+) -> Result<Response<Body>, Box<dyn Error>> {
+    let (parts, body) = request.into_parts();
+    let mut body = body::to_bytes(body).await?;
+    let mut method = parts.method.clone();
+    let mut uri = parts.uri.clone();
+    let mut visited = HashSet::new();
+    let mut redirects = 0;
+
+    loop {
+        visited.insert(redirect_key(&target));
+        let mut builder = Request::builder()
+            .method(method.clone())
+            .uri(uri.clone())
+            .version(parts.version);
+        for (name, value) in parts.headers.iter() {
+            if name != hyper::header::HOST {
+                builder = builder.header(name, value);
+            }
+        }
+        let request = builder
+            .header(hyper::header::HOST, host.as_str())
+            .body(Body::from(body.clone()))?;
+        let response = forward_request(
+            request,
+            replicas,
+            redis_param,
+            phonebook_param,
+            validation_config,
+            response_cache,
+            asset_store,
+            coalescer,
+            logger.clone(),
+            target,
+        )
+        .await?;
+
+        let status = response.status();
+        if !matches!(status.as_u16(), 301..=303 | 307 | 308) {
+            return Ok(response);
+        }
+        let location = match response
+            .headers()
+            .get(hyper::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| location.parse::<hyper::Uri>().ok())
+        {
+            Some(location) => location,
+            None => return Ok(response),
+        };
+        //an absolute location to another host is only followed if the host is served by the proxy.
+        let (next_host, host_only) = match location.authority() {
+            Some(authority) => (
+                authority.as_str().to_string(),
+                !authority.as_str().eq_ignore_ascii_case(&host),
+            ),
+            None => (host.clone(), false),
+        };
+        let next_target = match resolve_target(
+            &next_host,
+            &location,
+            host_only,
+            domains,
+            redis_param,
+            phonebook_param,
+            dns_aliases,
+            alias_cache,
+            logger,
+        )
+        .await
+        {
+            Some(next_target) => next_target,
+            None => {
+                slog::debug!(logger, "External redirect to {} returned", location);
+                return Ok(response);
+            }
+        };
+        if visited.contains(&redirect_key(&next_target)) {
+            slog::warn!(logger, "Redirect loop detected on {}", location);
+            return Ok(Response::builder()
+                .status(StatusCode::LOOP_DETECTED)
+                .header(IC_ERROR_HEADER, "redirect_loop")
+                .body(format!("Redirect loop detected on {}", location).into())?);
+        }
+        redirects += 1;
+        if redirects > max_redirects {
+            slog::warn!(logger, "Too many redirects, {} returned", location);
+            return Ok(response);
+        }
+
+        slog::debug!(logger, "Follow redirect {} to {}", status, location);
+        //301, 302 and 303 change the method to GET, 307 and 308 keep the method and the body.
+        if matches!(status.as_u16(), 301..=303) && method != hyper::Method::HEAD {
+            method = hyper::Method::GET;
+            body = Bytes::new();
+        }
+        uri = location;
+        host = next_host;
+        target = next_target;
+    }
+}
+
+//canisters are sorted because their order changes at each resolution.
+fn redirect_key(target: &TargetCanisterParams) -> (Vec<Principal>, String) {
+    let mut canister_ids = target.canister_ids.clone();
+    canister_ids.sort();
+    (canister_ids, target.found_uri.clone())
+}
+
 async fn update_redis_thread(
     redis_url: &str,
     mut redis_rx: mpsc::Receiver<(String, String)>,
     redis_cache_timout: usize,
+    redis_negative_cache_timout: usize,
     logger: slog::Logger,
 ) -> Result<(), Box<dyn Error>> {
     let redis_client = redis::Client::open(redis_url)?;
@@ -533,9 +1489,14 @@ async fn update_redis_thread(
             alias,
             canister_id,
         );
+        let timeout = if canister_id == NEGATIVE_ALIAS_VALUE {
+            redis_negative_cache_timout
+        } else {
+            redis_cache_timout
+        };
         if let Err(err) = redis_client
             .get_connection()
-            .and_then(|mut con| con.set_ex::<_, _, ()>(&alias, &canister_id, redis_cache_timout))
+            .and_then(|mut con| con.set_ex::<_, _, ()>(&alias, &canister_id, timeout))
         {
             slog::error!(logger, "Error during Redis cache update: {}", err);
         }
@@ -548,12 +1509,61 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let logger = logging::setup_logging(&opts);
 
-    // Prepare a list of agents for each backend replicas.
-    let replicas = Mutex::new(opts.replica.clone());
+    // Prepare an agent for each backend replicas, shared by all the requests.
+    let replicas = Arc::new(ReplicaPool::new(
+        &opts.replica,
+        opts.fetch_root_key,
+        opts.phonebook_id.as_deref(),
+        opts.unhealthy_threshold,
+        &logger,
+    )?);
 
-    let counter = AtomicUsize::new(0);
     let debug = opts.debug;
-    let fetch_root_key = opts.fetch_root_key;
+    let max_redirects = if opts.follow_redirects {
+        opts.max_redirects
+    } else {
+        0
+    };
+    let domains = Arc::new(
+        opts.domain
+            .iter()
+            .map(|domain| domain.trim().trim_matches('.').to_ascii_lowercase())
+            .collect::<Vec<_>>(),
+    );
+    let dns_aliases = Arc::new(DnsAliasConfig::new(
+        &opts.dns_alias,
+        opts.dns_alias_file.as_deref(),
+    )?);
+    let alias_cache = Arc::new(AliasCache::new(
+        opts.alias_cache_size,
+        Duration::from_secs(opts.alias_cache_timeout),
+        Duration::from_secs(opts.negative_cache_timeout),
+    ));
+    let validation_config = Arc::new(ValidationConfig::new(
+        Duration::from_secs(opts.max_cert_time_offset),
+        &opts.certification_mode,
+        &opts.canister_certification_mode,
+        opts.skip_body_verification,
+        RawAccess::new(opts.disable_raw, &opts.raw_allowlist)?,
+        DecompressionLimits {
+            max_size: Some(opts.max_decompressed_size).filter(|size| *size > 0),
+            max_ratio: Some(opts.max_decompression_ratio).filter(|ratio| *ratio > 0),
+        },
+        &opts.canister_decompression_limits,
+    )?);
+    let response_cache = Arc::new(ResponseCache::new(
+        opts.response_cache_size,
+        Duration::from_secs(opts.stale_if_error),
+    ));
+    let asset_store = match &opts.asset_store_dir {
+        Some(dir) => Some(Arc::new(AssetStore::open(dir, opts.asset_store_size)?)),
+        None => None,
+    };
+    let coalescer = if opts.disable_request_coalescing {
+        None
+    } else {
+        Some(Arc::new(Coalescer::new(opts.coalescing_buffer_size)))
+    };
 
     //create Redis cache update channel.
     //A cache entry is send to the channel and
@@ -562,6 +1572,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     let redis_logger = logger.clone();
     let th_redis_url = opts.redis_url.clone();
     let th_redis_cache_timeout = opts.redis_cache_timeout;
+    let th_redis_negative_cache_timeout = opts.negative_cache_timeout as usize;
 
     //start tokio runtime
     let runtime = tokio::runtime::Builder::new_multi_thread()
@@ -571,40 +1582,49 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     //create name alias resolution struct
     let redis_param: Option<RedisParam> = runtime.block_on(async {
-        RedisParam::try_new(Some(&opts.redis_url), Some(redis_tx), &logger).await
+        RedisParam::try_new(opts.redis_url.as_deref(), Some(redis_tx), &logger).await
     });
     let redis_param = Arc::new(redis_param);
+    runtime.block_on(replicas.fetch_root_keys(&logger));
 
     let service = make_service_fn(|_| {
+        let replicas = replicas.clone();
         let redis_param = redis_param.clone();
+        let domains = domains.clone();
+        let dns_aliases = dns_aliases.clone();
+        let alias_cache = alias_cache.clone();
+        let validation_config = validation_config.clone();
+        let response_cache = response_cache.clone();
+        let asset_store = asset_store.clone();
+        let coalescer = coalescer.clone();
         let logger = logger.clone();
 
-        // Select an agent.
-        let replica_url_array = replicas.lock().unwrap();
-        let count = counter.fetch_add(1, Ordering::SeqCst);
-        let replica_url = replica_url_array
-            .get(count % replica_url_array.len())
-            .unwrap_or_else(|| unreachable!());
-        let replica_url = replica_url.clone();
-        slog::debug!(logger, "make service Replica URL: {}", replica_url);
-
-        let phone_book_id = opts.phonebook_id.clone();
-
         async move {
             Ok::<_, Infallible>(service_fn(move |req| {
                 let logger = logger.clone();
+                let replicas = replicas.clone();
                 let redis_param = redis_param.clone();
-                //update phone book canister call with network replica
-                let phonebook_param =
-                    PhoneBookCanisterParam::new(&phone_book_id, &replica_url, &logger).ok();
+                let domains = domains.clone();
+                let dns_aliases = dns_aliases.clone();
+                let alias_cache = alias_cache.clone();
+                let validation_config = validation_config.clone();
+                let response_cache = response_cache.clone();
+                let asset_store = asset_store.clone();
+                let coalescer = coalescer.clone();
 
                 handle_request(
                     req,
-                    replica_url.clone(),
+                    replicas,
                     redis_param,
-                    phonebook_param,
+                    domains,
+                    dns_aliases,
+                    alias_cache,
+                    validation_config,
+                    response_cache,
+                    asset_store,
+                    coalescer,
+                    max_redirects,
                     logger,
-                    fetch_root_key,
                     debug,
                 )
             }))
@@ -617,23 +1637,35 @@ fn main() -> Result<(), Box<dyn Error>> {
         opts.address
     );
 
-    runtime.spawn(async move {
-        if let Err(err) = update_redis_thread(
-            &th_redis_url,
-            redis_rx,
-            th_redis_cache_timeout,
-            redis_logger.clone(),
-        )
-        .await
-        {
-            slog::error!(
-                redis_logger,
-                "Error Bad Redis Url can't start client connection: {} for url:{}",
-                err,
-                &th_redis_url
-            );
-        }
-    });
+    if opts.health_check_interval > 0 {
+        runtime.spawn(replicas.clone().run_health_checks(
+            Duration::from_secs(opts.health_check_interval),
+            logger.clone(),
+        ));
+    }
+
+    if let Some(th_redis_url) = th_redis_url {
+        runtime.spawn(async move {
+            if let Err(err) = update_redis_thread(
+                &th_redis_url,
+                redis_rx,
+                th_redis_cache_timeout,
+                th_redis_negative_cache_timeout,
+                redis_logger.clone(),
+            )
+            .await
+            {
+                slog::error!(
+                    redis_logger,
+                    "Error Bad Redis Url can't start client connection: {} for url:{}",
+                    err,
+                    &th_redis_url
+                );
+            }
+        });
+    } else {
+        slog::info!(logger, "No Redis url, only the in-process alias cache is used.");
+    }
     runtime.block_on(async {
         let server = Server::bind(&opts.address).serve(service);
         server.await?;
@@ -0,0 +1,381 @@
+use candid::{
+    parser::value::{IDLField, IDLValue},
+    types::Label,
+    Nat,
+};
+use hyper::{body::Bytes, header, HeaderMap};
+use ic_utils::interfaces::http_request::{HeaderField, Token};
+
+// idl hash of the `index` field of the streaming callback token.
+const TOKEN_INDEX_LABEL: u32 = 3_189_021_458;
+
+/// An inclusive byte range of the response body.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn content_range(&self, length: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, length)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+    Satisfiable(ByteRange),
+    Unsatisfiable,
+}
+
+/// The range to return for a request with a `Range` header, given the headers, the entity
+/// tag and the total length of the response. `None` means the whole body is returned:
+/// no or invalid `Range` header, multiple ranges, or an `If-Range` that doesn't match.
+pub fn requested_range(
+    request_headers: &HeaderMap,
+    response_headers: &[HeaderField],
+    etag: Option<&str>,
+    length: u64,
+) -> Option<RangeRequest> {
+    let range = request_headers.get(header::RANGE)?.to_str().ok()?;
+    if let Some(if_range) = request_headers.get(header::IF_RANGE) {
+        let if_range = if_range.to_str().ok()?;
+        let last_modified = response_header(response_headers, header::LAST_MODIFIED.as_str());
+        if !if_range_matches(if_range, etag, last_modified) {
+            return None;
+        }
+    }
+    parse_range(range, length)
+}
+
+/// The total length of the response from its `Content-Length` header.
+pub fn content_length(response_headers: &[HeaderField]) -> Option<u64> {
+    response_header(response_headers, header::CONTENT_LENGTH.as_str())?
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn response_header<'a>(headers: &'a [HeaderField], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|HeaderField(header_name, _)| header_name.eq_ignore_ascii_case(name))
+        .map(|HeaderField(_, value)| value.as_ref())
+}
+
+// Parse a single `bytes=` range. Suffix ranges (`bytes=-500`) and open ranges
+// (`bytes=500-`) are supported, the end is truncated to the length of the body.
+fn parse_range(value: &str, length: u64) -> Option<RangeRequest> {
+    let range = value.trim().strip_prefix("bytes=")?.trim();
+    if range.contains(',') {
+        return None;
+    }
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || length == 0 {
+            return Some(RangeRequest::Unsatisfiable);
+        }
+        return Some(RangeRequest::Satisfiable(ByteRange {
+            start: length.saturating_sub(suffix),
+            end: length - 1,
+        }));
+    }
+    let start: u64 = start.parse().ok()?;
+    let end = if end.is_empty() {
+        u64::MAX
+    } else {
+        end.parse().ok()?
+    };
+    if end < start {
+        return None;
+    }
+    if start >= length {
+        return Some(RangeRequest::Unsatisfiable);
+    }
+    Some(RangeRequest::Satisfiable(ByteRange {
+        start,
+        end: end.min(length - 1),
+    }))
+}
+
+// `If-Range` holds either an entity tag, compared with the strong comparison,
+// or a date that must be equal to the `Last-Modified` of the response.
+fn if_range_matches(if_range: &str, etag: Option<&str>, last_modified: Option<&str>) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        match etag {
+            Some(etag) => {
+                !if_range.starts_with("W/") && !etag.starts_with("W/") && etag.trim() == if_range
+            }
+            None => false,
+        }
+    } else {
+        last_modified.map_or(false, |last_modified| last_modified.trim() == if_range)
+    }
+}
+
+/// Position of the streamed chunks in the body, to send only the bytes of the range.
+/// Skipping chunks expects them to have the size of the first one, except the last one,
+/// the chunks fetched after skipping are checked against this layout.
+pub struct RangeWindow {
+    range: ByteRange,
+    position: u64,
+    skipped: Option<SkippedChunks>,
+}
+
+// The layout expected after skipping chunks, and the position before the skip.
+struct SkippedChunks {
+    chunk_size: u64,
+    length: u64,
+    position: u64,
+}
+
+impl RangeWindow {
+    pub fn new(range: ByteRange) -> Self {
+        RangeWindow {
+            range,
+            position: 0,
+            skipped: None,
+        }
+    }
+
+    /// The part of the chunk inside the range. Must be called for each chunk in order.
+    pub fn trim(&mut self, chunk: &[u8]) -> Bytes {
+        let chunk_start = self.position;
+        let chunk_end = chunk_start + chunk.len() as u64;
+        self.position = chunk_end;
+        let start = self.range.start.clamp(chunk_start, chunk_end);
+        let end = (self.range.end + 1).clamp(chunk_start, chunk_end);
+        Bytes::copy_from_slice(&chunk[(start - chunk_start) as usize..(end - chunk_start) as usize])
+    }
+
+    /// All the bytes of the range have been sent, no other chunk is needed.
+    pub fn is_done(&self) -> bool {
+        self.position > self.range.end
+    }
+
+    /// Skip the chunks before the range by changing the `index` of the callback token,
+    /// `length` is the length of the body. The token is returned unchanged if it has no
+    /// index or no chunk can be skipped, the skipped chunks are then fetched and dropped.
+    pub fn skip_chunks(&mut self, token: Token, chunk_size: u64, length: u64) -> Token {
+        if chunk_size == 0 || self.position % chunk_size != 0 {
+            return token;
+        }
+        let index = self.range.start / chunk_size;
+        if index <= self.position / chunk_size {
+            return token;
+        }
+        match token {
+            Token(IDLValue::Record(fields)) if fields.iter().any(is_index_field) => {
+                self.skipped = Some(SkippedChunks {
+                    chunk_size,
+                    length,
+                    position: self.position,
+                });
+                self.position = index * chunk_size;
+                let fields = fields
+                    .into_iter()
+                    .map(|field| {
+                        if is_index_field(&field) {
+                            IDLField {
+                                id: field.id,
+                                val: IDLValue::Nat(Nat::from(index)),
+                            }
+                        } else {
+                            field
+                        }
+                    })
+                    .collect();
+                Token(IDLValue::Record(fields))
+            }
+            token => token,
+        }
+    }
+
+    /// Check that a chunk fetched after skipping chunks is at the expected position: all
+    /// the chunks but the last one have the size of the first one, and the last one ends
+    /// at the length of the body. Always `true` when no chunk was skipped.
+    pub fn check_chunk(&self, chunk_len: usize, last: bool) -> bool {
+        match &self.skipped {
+            Some(skipped) => {
+                let end = self.position + chunk_len as u64;
+                if last {
+                    end == skipped.length
+                } else {
+                    chunk_len as u64 == skipped.chunk_size && end < skipped.length
+                }
+            }
+            None => true,
+        }
+    }
+
+    /// Go back to the position before the skipped chunks, they are fetched from the
+    /// token given to `skip_chunks`. `false` when bytes of the range were already sent.
+    pub fn restart(&mut self) -> bool {
+        match &self.skipped {
+            Some(skipped) if self.position <= self.range.start => {
+                self.position = skipped.position;
+                self.skipped = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+fn is_index_field(field: &IDLField) -> bool {
+    field.id == Label::Id(TOKEN_INDEX_LABEL) && matches!(field.val, IDLValue::Nat(_))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn range(start: u64, end: u64) -> Option<RangeRequest> {
+        Some(RangeRequest::Satisfiable(ByteRange { start, end }))
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(range(0, 99), parse_range("bytes=0-99", 1000));
+        assert_eq!(range(500, 999), parse_range("bytes=500-", 1000));
+        assert_eq!(range(900, 999), parse_range("bytes=-100", 1000));
+        assert_eq!(range(0, 999), parse_range("bytes=-2000", 1000));
+        assert_eq!(range(990, 999), parse_range("bytes=990-2000", 1000));
+        assert_eq!(
+            Some(RangeRequest::Unsatisfiable),
+            parse_range("bytes=1000-", 1000)
+        );
+        assert_eq!(
+            Some(RangeRequest::Unsatisfiable),
+            parse_range("bytes=-0", 1000)
+        );
+        assert_eq!(None, parse_range("bytes=0-10,20-30", 1000));
+        assert_eq!(None, parse_range("bytes=10-5", 1000));
+        assert_eq!(None, parse_range("items=0-10", 1000));
+        assert_eq!(None, parse_range("bytes=a-10", 1000));
+    }
+
+    #[test]
+    fn test_requested_range() {
+        let response_headers = vec![HeaderField("Content-Length".into(), "1000".into())];
+        assert_eq!(Some(1000), content_length(&response_headers));
+        let etag = Some("\"abc\"");
+
+        let mut request_headers = HeaderMap::new();
+        assert_eq!(
+            None,
+            requested_range(&request_headers, &response_headers, etag, 1000)
+        );
+        request_headers.insert(header::RANGE, "bytes=0-9".parse().unwrap());
+        assert_eq!(
+            range(0, 9),
+            requested_range(&request_headers, &response_headers, etag, 1000)
+        );
+        request_headers.insert(header::IF_RANGE, "\"abc\"".parse().unwrap());
+        assert_eq!(
+            range(0, 9),
+            requested_range(&request_headers, &response_headers, etag, 1000)
+        );
+        //without an entity tag the validator can't match.
+        assert_eq!(
+            None,
+            requested_range(&request_headers, &response_headers, None, 1000)
+        );
+        //the representation changed, the whole body is returned.
+        request_headers.insert(header::IF_RANGE, "\"def\"".parse().unwrap());
+        assert_eq!(
+            None,
+            requested_range(&request_headers, &response_headers, etag, 1000)
+        );
+        request_headers.insert(header::IF_RANGE, "W/\"abc\"".parse().unwrap());
+        assert_eq!(
+            None,
+            requested_range(&request_headers, &response_headers, etag, 1000)
+        );
+    }
+
+    #[test]
+    fn test_range_window() {
+        let mut window = RangeWindow::new(ByteRange { start: 5, end: 14 });
+        assert_eq!(Bytes::from_static(b"56789"), window.trim(b"0123456789"));
+        assert!(!window.is_done());
+        assert_eq!(Bytes::from_static(b"abcde"), window.trim(b"abcdefghij"));
+        assert!(window.is_done());
+
+        let index = IDLField {
+            id: Label::Id(TOKEN_INDEX_LABEL),
+            val: IDLValue::Nat(Nat::from(1u64)),
+        };
+        let key = IDLField {
+            id: Label::Id(5_343_647),
+            val: IDLValue::Text("/video.mp4".to_string()),
+        };
+        let mut window = RangeWindow::new(ByteRange { start: 35, end: 44 });
+        window.trim(b"0123456789");
+        let Token(token) =
+            window.skip_chunks(Token(IDLValue::Record(vec![index, key.clone()])), 10, 50);
+        assert_eq!(
+            IDLValue::Record(vec![
+                IDLField {
+                    id: Label::Id(TOKEN_INDEX_LABEL),
+                    val: IDLValue::Nat(Nat::from(3u64)),
+                },
+                key.clone(),
+            ]),
+            token
+        );
+        assert_eq!(Bytes::from_static(b"56789"), window.trim(b"0123456789"));
+
+        //without index the chunks are not skipped.
+        let mut window = RangeWindow::new(ByteRange { start: 35, end: 44 });
+        window.trim(b"0123456789");
+        let token = IDLValue::Record(vec![key]);
+        let Token(skipped) = window.skip_chunks(Token(token.clone()), 10, 50);
+        assert_eq!(token, skipped);
+        assert_eq!(Bytes::new(), window.trim(b"0123456789"));
+    }
+
+    #[test]
+    fn test_range_window_check_chunk() {
+        let token = || {
+            Token(IDLValue::Record(vec![IDLField {
+                id: Label::Id(TOKEN_INDEX_LABEL),
+                val: IDLValue::Nat(Nat::from(1u64)),
+            }]))
+        };
+
+        //the chunks have the size of the first one, the last one ends at the length.
+        let mut window = RangeWindow::new(ByteRange { start: 25, end: 34 });
+        window.trim(b"0123456789");
+        window.skip_chunks(token(), 10, 35);
+        assert!(window.check_chunk(10, false));
+        window.trim(b"0123456789");
+        assert!(window.check_chunk(5, true));
+        assert!(!window.check_chunk(10, true));
+
+        //a chunk of another size before any byte of the range is sent, the skipped
+        //chunks are fetched.
+        let mut window = RangeWindow::new(ByteRange { start: 25, end: 34 });
+        window.trim(b"0123456789");
+        window.skip_chunks(token(), 10, 35);
+        assert!(!window.check_chunk(5, false));
+        assert!(window.restart());
+        assert!(window.check_chunk(5, false));
+        assert_eq!(Bytes::new(), window.trim(b"01234"));
+
+        //the last chunk doesn't end at the length once the range is started.
+        let mut window = RangeWindow::new(ByteRange { start: 25, end: 34 });
+        window.trim(b"0123456789");
+        window.skip_chunks(token(), 10, 35);
+        window.trim(b"0123456789");
+        assert!(!window.check_chunk(10, true));
+        assert!(!window.restart());
+    }
+}
@@ -0,0 +1,291 @@
+use crate::canister::PhoneBookCanisterParam;
+use ic_agent::{agent::http_transport::ReqwestHttpReplicaV2Transport, Agent, AgentError};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// A backend replica with its long-lived agent. The agent, and so its HTTP connection pool,
+/// is created at startup and shared by all the requests sent to this replica.
+pub struct Replica {
+    pub url: String,
+    pub agent: Agent,
+    pub phonebook_param: Option<PhoneBookCanisterParam>,
+    fetch_root_key: bool,
+    root_key_fetched: AtomicBool,
+    healthy: AtomicBool,
+    //consecutive failed health checks.
+    failures: AtomicUsize,
+}
+
+impl Replica {
+    pub fn new(
+        url: &str,
+        fetch_root_key: bool,
+        phonebook_id: Option<&str>,
+        logger: &slog::Logger,
+    ) -> Result<Self, AgentError> {
+        let agent = Agent::builder()
+            .with_transport(ReqwestHttpReplicaV2Transport::create(url)?)
+            .build()?;
+        //the phone book canister is called with the same agent.
+        let phonebook_param = phonebook_id.and_then(|phonebook_id| {
+            PhoneBookCanisterParam::new(phonebook_id, agent.clone(), logger).ok()
+        });
+        Ok(Replica {
+            url: url.to_string(),
+            agent,
+            phonebook_param,
+            fetch_root_key,
+            root_key_fetched: AtomicBool::new(false),
+            healthy: AtomicBool::new(true),
+            failures: AtomicUsize::new(0),
+        })
+    }
+
+    /// Fetch the root key when `--fetch-root-key` is set. The key is fetched once,
+    /// and fetched again only after a failed fetch.
+    pub async fn ensure_root_key(&self) -> Result<(), AgentError> {
+        if self.fetch_root_key && !self.root_key_fetched.load(Ordering::Acquire) {
+            self.agent.fetch_root_key().await?;
+            self.root_key_fetched.store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// Fetch the root key again at the next request, after a certificate failed verification
+    /// with `--fetch-root-key`, the replica may have been restarted with a new key.
+    pub fn invalidate_root_key(&self) {
+        self.root_key_fetched.store(false, Ordering::Release);
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Acquire)
+    }
+
+    //call the replica `/api/v2/status` end point.
+    async fn check_health(&self, timeout: Duration) -> Result<(), String> {
+        match tokio::time::timeout(timeout, self.agent.status()).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(err)) => Err(err.to_string()),
+            Err(_) => Err("status request timed out".to_string()),
+        }
+    }
+}
+
+/// The replicas passed with `--replica`. A replica is chosen round-robin for each request
+/// among the healthy ones, the other healthy replicas are used to retry failed queries.
+pub struct ReplicaPool {
+    replicas: Vec<Arc<Replica>>,
+    counter: AtomicUsize,
+    //number of consecutive failed health checks before a replica is ejected.
+    unhealthy_threshold: usize,
+}
+
+impl ReplicaPool {
+    pub fn new(
+        urls: &[String],
+        fetch_root_key: bool,
+        phonebook_id: Option<&str>,
+        unhealthy_threshold: usize,
+        logger: &slog::Logger,
+    ) -> Result<Self, AgentError> {
+        let replicas = urls
+            .iter()
+            .map(|url| Replica::new(url, fetch_root_key, phonebook_id, logger).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        if replicas.is_empty() {
+            return Err(AgentError::InvalidReplicaUrl(
+                "No replica defined".to_string(),
+            ));
+        }
+        Ok(ReplicaPool {
+            replicas,
+            counter: AtomicUsize::new(0),
+            unhealthy_threshold: unhealthy_threshold.max(1),
+        })
+    }
+
+    /// Fetch the root key of all the replicas. A failure is logged and the fetch
+    /// is retried at the next request sent to the replica.
+    pub async fn fetch_root_keys(&self, logger: &slog::Logger) {
+        for replica in &self.replicas {
+            if let Err(err) = replica.ensure_root_key().await {
+                slog::error!(
+                    logger,
+                    "Error could not fetch the root key of replica {}: {}",
+                    replica.url,
+                    err
+                );
+            }
+        }
+    }
+
+    /// The replicas to use for one request: the healthy replicas, starting round-robin.
+    /// When no replica is healthy, all of them are tried. Never empty.
+    pub fn select(&self) -> Vec<Arc<Replica>> {
+        let mut replicas = self
+            .replicas
+            .iter()
+            .filter(|replica| replica.is_healthy())
+            .cloned()
+            .collect::<Vec<_>>();
+        if replicas.is_empty() {
+            replicas = self.replicas.clone();
+        }
+        let count = self.counter.fetch_add(1, Ordering::SeqCst);
+        let len = replicas.len();
+        replicas.rotate_left(count % len);
+        replicas
+    }
+
+    /// Check the status of all the replicas. A replica is ejected after `unhealthy_threshold`
+    /// consecutive failed checks and reinstated after the first successful one.
+    pub async fn check_health(&self, timeout: Duration, logger: &slog::Logger) {
+        for replica in &self.replicas {
+            let health = replica.check_health(timeout).await;
+            self.record_health(replica, health, logger);
+        }
+    }
+
+    // Update the health of a replica with the result of a check.
+    fn record_health(&self, replica: &Replica, health: Result<(), String>, logger: &slog::Logger) {
+        match health {
+            Ok(()) => {
+                replica.failures.store(0, Ordering::Release);
+                if !replica.healthy.swap(true, Ordering::AcqRel) {
+                    slog::info!(
+                        logger,
+                        "Replica {} is healthy again, reinstated",
+                        replica.url
+                    );
+                }
+            }
+            Err(err) => {
+                let failures = replica.failures.fetch_add(1, Ordering::AcqRel) + 1;
+                slog::warn!(
+                    logger,
+                    "Replica {} health check failed ({} in a row): {}",
+                    replica.url,
+                    failures,
+                    err
+                );
+                if failures >= self.unhealthy_threshold
+                    && replica.healthy.swap(false, Ordering::AcqRel)
+                {
+                    slog::error!(logger, "Replica {} is unhealthy, ejected", replica.url);
+                }
+            }
+        }
+    }
+
+    /// Check the replicas health every `interval` until the process stops.
+    pub async fn run_health_checks(self: Arc<Self>, interval: Duration, logger: slog::Logger) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            self.check_health(interval, &logger).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pool(unhealthy_threshold: usize) -> ReplicaPool {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let urls = [
+            "http://127.0.0.1:8001",
+            "http://127.0.0.1:8002",
+            "http://127.0.0.1:8003",
+        ]
+        .iter()
+        .map(|url| url.to_string())
+        .collect::<Vec<_>>();
+        ReplicaPool::new(&urls, false, None, unhealthy_threshold, &logger).unwrap()
+    }
+
+    fn urls(replicas: &[Arc<Replica>]) -> Vec<&str> {
+        replicas
+            .iter()
+            .map(|replica| replica.url.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_select() {
+        let pool = pool(1);
+        //each request starts with the next replica, the others are used for the retries.
+        assert_eq!(
+            vec![
+                "http://127.0.0.1:8001",
+                "http://127.0.0.1:8002",
+                "http://127.0.0.1:8003"
+            ],
+            urls(&pool.select())
+        );
+        assert_eq!(
+            vec![
+                "http://127.0.0.1:8002",
+                "http://127.0.0.1:8003",
+                "http://127.0.0.1:8001"
+            ],
+            urls(&pool.select())
+        );
+        assert_eq!(
+            vec![
+                "http://127.0.0.1:8003",
+                "http://127.0.0.1:8001",
+                "http://127.0.0.1:8002"
+            ],
+            urls(&pool.select())
+        );
+        assert_eq!("http://127.0.0.1:8001", pool.select()[0].url);
+
+        //the unhealthy replicas are skipped, all the replicas are tried when none is healthy.
+        pool.replicas[1].healthy.store(false, Ordering::Release);
+        let selected = pool.select();
+        assert_eq!(2, selected.len());
+        assert!(!urls(&selected).contains(&"http://127.0.0.1:8002"));
+        for replica in &pool.replicas {
+            replica.healthy.store(false, Ordering::Release);
+        }
+        assert_eq!(3, pool.select().len());
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        assert!(ReplicaPool::new(&[], false, None, 1, &logger).is_err());
+    }
+
+    #[test]
+    fn test_unhealthy_threshold() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let pool = pool(2);
+        let replica = pool.replicas[0].clone();
+        let failed = || Err("status request timed out".to_string());
+
+        //a replica is ejected after `unhealthy_threshold` consecutive failed checks.
+        pool.record_health(&replica, failed(), &logger);
+        assert!(replica.is_healthy());
+        pool.record_health(&replica, Ok(()), &logger);
+        pool.record_health(&replica, failed(), &logger);
+        assert!(replica.is_healthy());
+        pool.record_health(&replica, failed(), &logger);
+        assert!(!replica.is_healthy());
+        assert!(!urls(&pool.select()).contains(&replica.url.as_str()));
+
+        //and reinstated after the first successful check.
+        pool.record_health(&replica, Ok(()), &logger);
+        assert!(replica.is_healthy());
+        assert_eq!(3, pool.select().len());
+
+        //a threshold of 0 ejects the replica at the first failure.
+        let pool = self::pool(0);
+        let replica = pool.replicas[0].clone();
+        pool.record_health(&replica, failed(), &logger);
+        assert!(!replica.is_healthy());
+    }
+}
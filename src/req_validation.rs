@@ -1,35 +1,376 @@
 use ic_agent::{
 	export::Principal,
-	ic_types::{hash_tree::LookupResult, HashTree},
+	ic_types::{
+		hash_tree::{Label as TreeLabel, LookupResult},
+		HashTree,
+	},
 	lookup_value, Agent, AgentError, Certificate,
 };
-use flate2::read::{DeflateDecoder, GzDecoder};
 use sha2::{Digest, Sha256};
-use hyper::{Uri, body::Bytes};
+use hyper::body::Bytes;
+use percent_encoding::percent_decode_str;
+use crate::body_hash::{BodyHasher, DecompressionLimits};
+use crate::http_certification::{
+	expression_hash, request_hash, response_hash_with_body, response_headers_hash,
+	CertificateExpression,
+};
 use crate::ic_req_headers::{HeadersData};
-use std::{io::Read};
+use std::{
+	collections::{HashMap, HashSet},
+	fmt,
+	str::FromStr,
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use ic_utils::{
-	interfaces::http_request::{StreamingCallbackHttpResponse, Token},
+	interfaces::http_request::{HeaderField, StreamingCallbackHttpResponse, Token},
 };
 use candid::{
 	parser::{
-			value::{IDLValue},
+			value::{IDLField, IDLValue},
 	},
 	types::{Label},
 };
 
-// The limit of a buffer we should decompress ~10mb.
-const MAX_CHUNK_SIZE_TO_DECOMPRESS: usize = 1024;
-const MAX_CHUNKS_TO_DECOMPRESS: u64 = 10_240;
+/// What to do with the responses and the streaming chunks without certification.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CertificationMode {
+	/// Serve them.
+	Permissive,
+	/// Serve them and log a warning.
+	Warn,
+	/// Reject them.
+	Strict,
+}
+
+impl FromStr for CertificationMode {
+	type Err = String;
+
+	fn from_str(mode: &str) -> Result<Self, Self::Err> {
+		match mode.trim().to_ascii_lowercase().as_str() {
+			"permissive" => Ok(CertificationMode::Permissive),
+			"warn" => Ok(CertificationMode::Warn),
+			"strict" => Ok(CertificationMode::Strict),
+			_ => Err(format!(
+				"Error certification mode '{}' is not one of permissive, warn or strict",
+				mode
+			)),
+		}
+	}
+}
+
+/// Which canisters can be called without validation, with the `_raw` query parameter
+/// or a raw host.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RawAccess {
+	Enabled,
+	Disabled,
+	Allowlist(HashSet<Principal>),
+}
+
+impl RawAccess {
+	/// Build the access from `--disable-raw` and the `--raw-allowlist` canister ids.
+	pub fn new(disabled: bool, allowlist: &[String]) -> Result<Self, String> {
+		if disabled {
+			return Ok(RawAccess::Disabled);
+		}
+		if allowlist.is_empty() {
+			return Ok(RawAccess::Enabled);
+		}
+		allowlist
+			.iter()
+			.map(|canister_id| {
+				Principal::from_text(canister_id.trim()).map_err(|err| {
+					format!(
+						"Error raw allowlist canister id '{}' not a principal: {}",
+						canister_id, err
+					)
+				})
+			})
+			.collect::<Result<_, _>>()
+			.map(RawAccess::Allowlist)
+	}
+}
+
+/// Settings of the validation of the canister responses.
+#[derive(Clone, Debug)]
+pub struct ValidationConfig {
+	/// Maximum difference between the time of a certificate and the local time,
+	/// older or future certificates are rejected. Zero disables the check.
+	pub max_cert_time_offset: Duration,
+	pub certification_mode: CertificationMode,
+	/// Certification mode of the canisters that don't use the global one.
+	pub canister_certification_modes: HashMap<Principal, CertificationMode>,
+	/// Serve the responses that fail verification, the failures are logged.
+	pub skip_body_verification: bool,
+	pub raw_access: RawAccess,
+	/// Limits of the decoding of the compressed bodies in body verification.
+	pub decompression_limits: DecompressionLimits,
+	/// Decompression limits of the canisters that don't use the global ones.
+	pub canister_decompression_limits: HashMap<Principal, DecompressionLimits>,
+}
+
+impl ValidationConfig {
+	/// Build the config from the `--certification-mode` and the
+	/// `--canister-certification-mode` entries in the format `canister-id:mode`, and the
+	/// `--canister-decompression-limits` entries in the format `canister-id:max-size:max-ratio`.
+	pub fn new(
+		max_cert_time_offset: Duration,
+		certification_mode: &str,
+		canister_certification_modes: &[String],
+		skip_body_verification: bool,
+		raw_access: RawAccess,
+		decompression_limits: DecompressionLimits,
+		canister_decompression_limits: &[String],
+	) -> Result<Self, String> {
+		let canister_certification_modes = canister_certification_modes
+			.iter()
+			.map(|entry| {
+				let (canister_id, mode) = entry.rsplit_once(':').ok_or_else(|| {
+					format!(
+						"Error canister certification mode '{}' is not in the format canister-id:mode",
+						entry
+					)
+				})?;
+				let canister_id = Principal::from_text(canister_id.trim()).map_err(|err| {
+					format!(
+						"Error canister certification mode '{}' canister id not a principal: {}",
+						entry, err
+					)
+				})?;
+				Ok((canister_id, mode.parse()?))
+			})
+			.collect::<Result<_, String>>()?;
+		let canister_decompression_limits = canister_decompression_limits
+			.iter()
+			.map(|entry| parse_canister_decompression_limits(entry, decompression_limits))
+			.collect::<Result<_, String>>()?;
+		Ok(ValidationConfig {
+			max_cert_time_offset,
+			certification_mode: certification_mode.parse()?,
+			canister_certification_modes,
+			skip_body_verification,
+			raw_access,
+			decompression_limits,
+			canister_decompression_limits,
+		})
+	}
+
+	/// Whether the validation of the responses of the canister can be skipped.
+	pub fn allows_raw(&self, canister_id: &Principal) -> bool {
+		match &self.raw_access {
+			RawAccess::Enabled => true,
+			RawAccess::Disabled => false,
+			RawAccess::Allowlist(canister_ids) => canister_ids.contains(canister_id),
+		}
+	}
+
+	pub fn certification_mode(&self, canister_id: &Principal) -> CertificationMode {
+		self.canister_certification_modes
+			.get(canister_id)
+			.copied()
+			.unwrap_or(self.certification_mode)
+	}
+
+	pub fn decompression_limits(&self, canister_id: &Principal) -> DecompressionLimits {
+		self.canister_decompression_limits
+			.get(canister_id)
+			.copied()
+			.unwrap_or(self.decompression_limits)
+	}
+
+	/// All the chunks of a certified stream are certified: the hash of the whole body is
+	/// checked at the end of the stream, or the uncertified chunks are rejected in the strict
+	/// certification mode.
+	pub fn certifies_whole_stream(&self, canister_id: &Principal, whole_body_hash: bool) -> bool {
+		whole_body_hash || self.certification_mode(canister_id) == CertificationMode::Strict
+	}
+}
+
+// Parse a `canister-id:max-size:max-ratio` entry, an empty limit is the global one
+// and a zero limit is no limit.
+fn parse_canister_decompression_limits(
+	entry: &str,
+	global: DecompressionLimits,
+) -> Result<(Principal, DecompressionLimits), String> {
+	let invalid = || {
+		format!(
+			"Error canister decompression limits '{}' is not in the format canister-id:max-size:max-ratio",
+			entry
+		)
+	};
+	let (canister_id, max_size, max_ratio) = match entry.split(':').collect::<Vec<_>>()[..] {
+		[canister_id, max_size, max_ratio] => (canister_id, max_size, max_ratio),
+		_ => return Err(invalid()),
+	};
+	let canister_id = Principal::from_text(canister_id.trim()).map_err(|err| {
+		format!(
+			"Error canister decompression limits '{}' canister id not a principal: {}",
+			entry, err
+		)
+	})?;
+	let limit = |limit: &str, global: Option<u64>| match limit.trim() {
+		"" => Ok(global),
+		limit => limit
+			.parse::<u64>()
+			.map(|limit| if limit == 0 { None } else { Some(limit) })
+			.map_err(|_| invalid()),
+	};
+	Ok((
+		canister_id,
+		DecompressionLimits {
+			max_size: limit(max_size, global.max_size)?,
+			max_ratio: limit(max_ratio, global.max_ratio)?,
+		},
+	))
+}
+
+/// Why a canister response failed validation.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValidationError {
+	/// The certificate could not be decoded, or its signature or subnet delegation
+	/// does not verify against the root key.
+	Certificate(String),
+	/// The body, its hash or the tree does not match the certified data.
+	Body(String),
+	/// The certificate time is out of the `max_cert_time_offset` window.
+	CertificateTime(String),
+	/// The response or the chunk has no certification, in the strict certification mode.
+	Uncertified(String),
+}
+
+impl fmt::Display for ValidationError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			ValidationError::Certificate(message) => {
+				write!(f, "Certificate verification failed: {}", message)
+			}
+			ValidationError::Body(message) => write!(f, "{}", message),
+			ValidationError::CertificateTime(message) => {
+				write!(f, "Certificate time rejected: {}", message)
+			}
+			ValidationError::Uncertified(message) => write!(f, "{}", message),
+		}
+	}
+}
+
+/// The result of the validation of a served response.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verification {
+	/// The body is certified by the v1 certification.
+	CertifiedV1,
+	/// The response is certified by the v2 certification.
+	CertifiedV2,
+	/// The response has no certification, or a v2 `no_certification` expression.
+	Uncertified,
+	/// The validation is skipped with the `_raw` parameter or a raw host.
+	Skipped,
+	/// The response failed verification, it is served with `--skip-body-verification`.
+	Failed(ValidationError),
+}
+
+impl Verification {
+	/// The value of the `X-IC-Verification` header.
+	pub fn header_value(&self) -> &'static str {
+		match self {
+			Verification::CertifiedV1 => "certified-v1",
+			Verification::CertifiedV2 => "certified-v2",
+			Verification::Uncertified => "uncertified",
+			Verification::Skipped => "skipped",
+			Verification::Failed(_) => "failed",
+		}
+	}
+}
+
+/// The certified hash of a response body, known from the certificate and the tree before
+/// the body is received. A streamed body is hashed chunk by chunk and checked at the end.
+#[derive(Clone, Debug)]
+pub enum CertifiedBody {
+	/// The v1 certification: the sha256 of the decoded body is in the tree.
+	Decoded {
+		sha: Vec<u8>,
+		encoding: Option<String>,
+		limits: DecompressionLimits,
+	},
+	/// The v2 certification: the tree contains `path` followed by the response hash.
+	Response { tree: Vec<u8>, path: Vec<Vec<u8>>, headers_hash: [u8; 32] },
+}
+
+impl CertifiedBody {
+	/// The result of the validation once the body is checked.
+	pub fn verification(&self) -> Verification {
+		match self {
+			CertifiedBody::Decoded { .. } => Verification::CertifiedV1,
+			CertifiedBody::Response { .. } => Verification::CertifiedV2,
+		}
+	}
+
+	/// The certified sha256 of the decoded body and the encoding of the body, with the v1
+	/// certification.
+	pub fn decoded_sha(&self) -> Option<(&[u8], Option<&str>)> {
+		match self {
+			CertifiedBody::Decoded { sha, encoding, .. } => Some((sha, encoding.as_deref())),
+			CertifiedBody::Response { .. } => None,
+		}
+	}
+
+	/// An incremental hasher of the body, its hash is checked with `check_sha`.
+	pub fn hasher(&self) -> Result<BodyHasher, ValidationError> {
+		match self {
+			CertifiedBody::Decoded { encoding, limits, .. } => {
+				BodyHasher::new(encoding.as_deref(), *limits).map_err(ValidationError::Body)
+			}
+			CertifiedBody::Response { .. } => {
+				BodyHasher::new(None, DecompressionLimits::default()).map_err(ValidationError::Body)
+			}
+		}
+	}
+
+	/// Check a complete body.
+	pub fn check(&self, body: &[u8]) -> Result<(), ValidationError> {
+		let body_sha = match self {
+			CertifiedBody::Decoded { encoding, limits, .. } => {
+				decode_body_to_sha256(body, encoding.as_deref(), *limits).map_err(ValidationError::Body)?
+			}
+			CertifiedBody::Response { .. } => Sha256::digest(body).into(),
+		};
+		self.check_sha(&body_sha)
+	}
 
+	/// Check the sha256 of the whole body.
+	pub fn check_sha(&self, body_sha: &[u8; 32]) -> Result<(), ValidationError> {
+		let certified = match self {
+			CertifiedBody::Decoded { sha, .. } => sha.as_slice() == body_sha,
+			CertifiedBody::Response { tree, path, headers_hash } => {
+				let tree: HashTree = serde_cbor::from_slice(tree)
+					.map_err(|e| ValidationError::Body(AgentError::InvalidCborData(e).to_string()))?;
+				let mut path: Vec<TreeLabel> = path.iter().map(TreeLabel::from).collect();
+				path.push(response_hash_with_body(headers_hash, body_sha).into());
+				matches!(tree.lookup_path(&path), LookupResult::Found(_))
+			}
+		};
+		if certified {
+			Ok(())
+		} else {
+			Err(not_valid())
+		}
+	}
+}
 
+/// Validate a streamed chunk with the certification of the callback token returned with it.
+/// The last chunk is returned without token, it's part of the stream certified so far and has
+/// the verification of the previous chunk.
 pub fn validate_chunk(
 	callback_response: StreamingCallbackHttpResponse,
 	canister_id: Principal,
 	agent: &Agent,
-	uri: &Uri,
+	url: &str,
+	previous: &Verification,
+	config: &ValidationConfig,
 	logger: slog::Logger,
-) -> Result<(), String> {
+) -> Result<Verification, ValidationError> {
+	if callback_response.token.is_none() {
+		return Ok(previous.clone());
+	}
 	// let { body, token } = callback_response;
 	let fields  = match callback_response.token.clone() {
 		Some(Token(IDLValue::Record(fields))) => fields,
@@ -40,10 +381,19 @@ pub fn validate_chunk(
 	let token_tree = fields.iter().find(|&r| r.id == Label::Id(1_292_081_502));
 	let token_tree_path = fields.iter().find(|&r| r.id == Label::Id(3_577_787_238));
 
-	if token_cert.is_some() && token_tree.is_some() && token_tree_path.is_some()  {
-			let cert_bytes = base64::decode(token_cert.unwrap().val.to_string().replace("\"", "")).unwrap();
-			let tree_bytes = base64::decode(token_tree.unwrap().val.to_string().replace("\"", "")).unwrap();
-			let tree_path = token_tree_path.unwrap().val.to_string().replace("\"", "");
+	if let (Some(token_cert), Some(token_tree), Some(token_tree_path)) = (token_cert, token_tree, token_tree_path) {
+			let decode = |name: &str, field: &IDLField| {
+					base64::decode(field.val.to_string().replace("\"", "")).map_err(|e| {
+							ValidationError::Certificate(format!("Unable to decode {} of the callback token from base64: {}", name, e))
+					})
+			};
+			let (cert_bytes, tree_bytes) = match decode("certificate", token_cert)
+					.and_then(|cert_bytes| Ok((cert_bytes, decode("tree", token_tree)?)))
+			{
+					Ok(certificates) => certificates,
+					Err(err) => return skip_verification_failure(Err(err), config, &logger),
+			};
+			let tree_path = token_tree_path.val.to_string().replace("\"", "");
 
 			let body_valid = validate(
 					&HeadersData {
@@ -51,11 +401,15 @@ pub fn validate_chunk(
 							tree: Some(Ok(tree_bytes)),
 							encoding: None,
 							key: Some(tree_path),
+							version: None,
+							expr_path: None,
+							certificate_expression: None,
 					},
 					&canister_id,
 					agent,
-					uri,
+					url,
 					&Bytes::from(callback_response.body.clone()),
+					config,
 					logger.clone(),
 			);
 
@@ -67,124 +421,100 @@ pub fn validate_chunk(
 			return body_valid;
 	}
 
-	Ok(())
+	// The chunk is not certified by its token.
+	uncertified(&canister_id, config, "streaming chunk", &logger).map(|()| Verification::Uncertified)
 }
 
+/// Validate the response with the v1 certification. `url` is the url sent to the canister,
+/// its path is the tree key when the response has no `TREE-KEY` header.
 pub fn validate(
 	headers_data: &HeadersData,
 	canister_id: &Principal,
 	agent: &Agent,
-	uri: &Uri,
+	url: &str,
 	response_body: &[u8],
+	config: &ValidationConfig,
 	logger: slog::Logger,
-) -> Result<(), String> {
-	let body_sha = if let Some(body_sha) =
-			decode_body_to_sha256(response_body, headers_data.encoding.clone())
-	{
-			body_sha
-	} else {
-			return Err("Body could not be decoded".into());
-	};
+) -> Result<Verification, ValidationError> {
+	let body_valid = certified_body_v1(headers_data, canister_id, agent, url, config, &logger)
+			.and_then(|certified_body| check_body(certified_body, response_body));
+	skip_verification_failure(body_valid, config, &logger)
+}
 
-	let found_uri = str::replace(uri.path(), &format!("{}{}", "/-/", canister_id).to_string(), "");
-	let tree_key = if let Some(tree_key) = headers_data.key.as_ref() { tree_key } else { &found_uri };
+fn certified_body_v1(
+	headers_data: &HeadersData,
+	canister_id: &Principal,
+	agent: &Agent,
+	url: &str,
+	config: &ValidationConfig,
+	logger: &slog::Logger,
+) -> Result<Option<CertifiedBody>, ValidationError> {
+	let path = certified_path(url);
+	let tree_key = if let Some(tree_key) = headers_data.key.as_ref() { tree_key } else { &path };
 
-	let body_valid = match (
+	match (
 			headers_data.certificate.as_ref(),
 			headers_data.tree.as_ref(),
 	) {
-			(Some(Ok(certificate)), Some(Ok(tree))) => match validate_body(
-					Certificates { certificate, tree },
-					canister_id,
-					agent,
-					tree_key.to_string(),
-					&body_sha,
-					logger.clone(),
-			) {
-					Ok(true) => Ok(()),
-					Ok(false) => Err("Body does not pass verification".to_string()),
-					Err(e) => Err(format!("Certificate validation failed: {}", e)),
-			},
-			(Some(_), _) | (_, Some(_)) => Err("Body does not pass verification".to_string()),
+			(Some(Ok(certificate)), Some(Ok(tree))) => {
+					let sha = certified_sha(
+							Certificates { certificate, tree },
+							canister_id,
+							agent,
+							tree_key.to_string(),
+							config,
+							logger,
+					)?;
+					Ok(Some(CertifiedBody::Decoded {
+							sha,
+							encoding: headers_data.encoding.clone(),
+							limits: config.decompression_limits(canister_id),
+					}))
+			}
+			(Some(_), _) | (_, Some(_)) => Err(not_valid()),
 
-			// TODO: Remove this (FOLLOW-483)
 			// Canisters don't have to provide certified variables
-			// This should change in the future, grandfathering in current implementations
-			(None, None) => Ok(()),
-	};
-
-	if body_valid.is_err() && !cfg!(feature = "skip_body_verification") {
-			return body_valid;
+			// unless the certification mode of the canister is strict.
+			(None, None) => uncertified(canister_id, config, "response", logger).map(|()| None),
 	}
-
-	Ok(())
 }
 
-fn decode_body_to_sha256(body: &[u8], encoding: Option<String>) -> Option<[u8; 32]> {
-	let mut sha256 = Sha256::new();
-	let mut decoded = [0u8; MAX_CHUNK_SIZE_TO_DECOMPRESS];
-	match encoding.as_deref() {
-			Some("gzip") => {
-					let mut decoder = GzDecoder::new(body);
-					for _ in 0..MAX_CHUNKS_TO_DECOMPRESS {
-							let bytes = decoder.read(&mut decoded).ok()?;
-							if bytes == 0 {
-									return Some(sha256.finalize().into());
-							}
-							sha256.update(&decoded[0..bytes]);
-					}
-					if decoder.bytes().next().is_some() {
-							return None;
-					}
-			}
-			Some("deflate") => {
-					let mut decoder = DeflateDecoder::new(body);
-					for _ in 0..MAX_CHUNKS_TO_DECOMPRESS {
-							let bytes = decoder.read(&mut decoded).ok()?;
-							if bytes == 0 {
-									return Some(sha256.finalize().into());
-							}
-							sha256.update(&decoded[0..bytes]);
-					}
-					if decoder.bytes().next().is_some() {
-							return None;
-					}
-			}
-			_ => sha256.update(body),
-	};
-	Some(sha256.finalize().into())
-}
-
-struct Certificates<'a> {
-	certificate: &'a Vec<u8>,
-	tree: &'a Vec<u8>,
-}
-
-fn validate_body(
-	certificates: Certificates,
+// Verify the certificate and check that the tree is the certified data of the canister.
+fn verify_certificate(
+	cert: &Certificate,
+	tree: &HashTree,
 	canister_id: &Principal,
 	agent: &Agent,
-	tree_key: String,
-	body_sha: &[u8; 32],
-	logger: slog::Logger,
-) -> anyhow::Result<bool> {
-	let cert: Certificate =
-			serde_cbor::from_slice(certificates.certificate).map_err(AgentError::InvalidCborData)?;
-	let tree: HashTree =
-			serde_cbor::from_slice(certificates.tree).map_err(AgentError::InvalidCborData)?;
-
-	//TODO: does not pass verification
-	// if let Err(e) = agent.verify(&cert, *canister_id, false) {
-	//     slog::trace!(logger, ">> certificate failed verification: {}", e);
-	//     return Ok(false);
-	// }
+	config: &ValidationConfig,
+	logger: &slog::Logger,
+) -> Result<bool, ValidationError> {
+	// Check the BLS signature of the certificate with the root key of the agent,
+	// and the subnet delegation with the canister ranges of the subnet.
+	if let Err(e) = agent.verify(cert, *canister_id, false) {
+			slog::trace!(logger, ">> certificate failed verification: {}", e);
+			return Err(ValidationError::Certificate(e.to_string()));
+	}
+
+	// A valid certificate can be replayed with an old body, so its time must be recent.
+	if !config.max_cert_time_offset.is_zero() {
+			let time = lookup_value(cert, vec!["time".into()])
+					.map_err(|e| ValidationError::Certificate(e.to_string()))?;
+			let time = decode_leb128(time).ok_or_else(|| {
+					ValidationError::Certificate("Certificate time is not a valid leb128 number".into())
+			})?;
+			check_certificate_time(
+					Duration::from_nanos(time),
+					SystemTime::now(),
+					config.max_cert_time_offset,
+			)?;
+	}
 
 	let certified_data_path = vec![
 			"canister".into(),
 			canister_id.into(),
 			"certified_data".into(),
 	];
-	let witness = match lookup_value(&cert, certified_data_path) {
+	let witness = match lookup_value(cert, certified_data_path) {
 			Ok(witness) => witness,
 			Err(e) => {
 					slog::trace!(
@@ -208,22 +538,525 @@ fn validate_body(
 			return Ok(false);
 	}
 
+	Ok(true)
+}
+
+// The path of the url sent to the canister, percent-decoded, as certified by the canister.
+fn certified_path(url: &str) -> String {
+	let path = url.split(|c| c == '?' || c == '#').next().unwrap_or_default();
+	percent_decode_str(path).decode_utf8_lossy().into_owned()
+}
+
+fn not_valid() -> ValidationError {
+	ValidationError::Body("Body does not pass verification".to_string())
+}
+
+fn check_body(certified_body: Option<CertifiedBody>, body: &[u8]) -> Result<Verification, ValidationError> {
+	match certified_body {
+		Some(certified_body) => certified_body.check(body).map(|()| certified_body.verification()),
+		None => Ok(Verification::Uncertified),
+	}
+}
+
+/// Serve a response that failed verification with `--skip-body-verification`.
+pub fn skip_verification_failure(
+	body_valid: Result<Verification, ValidationError>,
+	config: &ValidationConfig,
+	logger: &slog::Logger,
+) -> Result<Verification, ValidationError> {
+	match body_valid {
+		Err(err) if config.skip_body_verification => {
+			slog::warn!(logger, "Verification failure skipped: {}", err);
+			Ok(Verification::Failed(err))
+		}
+		body_valid => body_valid,
+	}
+}
+
+// Apply the certification mode of the canister to a response or a chunk without certification.
+fn uncertified(
+	canister_id: &Principal,
+	config: &ValidationConfig,
+	what: &str,
+	logger: &slog::Logger,
+) -> Result<(), ValidationError> {
+	let message = format!("Canister {} returned an uncertified {}", canister_id, what);
+	match config.certification_mode(canister_id) {
+		CertificationMode::Permissive => Ok(()),
+		CertificationMode::Warn => {
+			slog::warn!(logger, "{}", message);
+			Ok(())
+		}
+		CertificationMode::Strict => Err(ValidationError::Uncertified(message)),
+	}
+}
+
+/// The request as received by the proxy, the fields certified by the v2 certification.
+pub struct CertifiedRequest<'a> {
+	pub method: &'a str,
+	/// The url sent to the canister.
+	pub url: &'a str,
+	pub headers: &'a [HeaderField<'a>],
+	pub body: &'a [u8],
+}
+
+/// The response of the canister, the fields certified by the v2 certification with the body.
+pub struct CertifiedResponse<'a> {
+	pub status_code: u16,
+	pub headers: &'a [HeaderField<'a>],
+}
+
+/// Validate the response with the v2 certification when the `IC-Certificate` header
+/// has a `version=2` field, with the v1 certification otherwise.
+#[allow(clippy::too_many_arguments)]
+pub fn validate_response(
+	headers_data: &HeadersData,
+	canister_id: &Principal,
+	agent: &Agent,
+	request: &CertifiedRequest,
+	response: &CertifiedResponse,
+	body: &[u8],
+	config: &ValidationConfig,
+	logger: slog::Logger,
+) -> Result<Verification, ValidationError> {
+	let body_valid = certified_body(headers_data, canister_id, agent, request, response, config, &logger)
+			.and_then(|certified_body| check_body(certified_body, body));
+	skip_verification_failure(body_valid, config, &logger)
+}
+
+/// Verify the certification of the response and return the certified hash of its body,
+/// `None` when the body is not certified: a `no_certification` expression, or a response
+/// without certification in a certification mode that serves it.
+pub fn certified_body(
+	headers_data: &HeadersData,
+	canister_id: &Principal,
+	agent: &Agent,
+	request: &CertifiedRequest,
+	response: &CertifiedResponse,
+	config: &ValidationConfig,
+	logger: &slog::Logger,
+) -> Result<Option<CertifiedBody>, ValidationError> {
+	if headers_data.version.map_or(true, |version| version < 2) {
+			certified_body_v1(headers_data, canister_id, agent, request.url, config, logger)
+	} else {
+			certified_body_v2(headers_data, canister_id, agent, request, response, config, logger)
+	}
+}
+
+fn certified_body_v2(
+	headers_data: &HeadersData,
+	canister_id: &Principal,
+	agent: &Agent,
+	request: &CertifiedRequest,
+	response: &CertifiedResponse,
+	config: &ValidationConfig,
+	logger: &slog::Logger,
+) -> Result<Option<CertifiedBody>, ValidationError> {
+	let (certificate, tree_bytes, expr_path, expression) = match (
+			headers_data.certificate.as_ref(),
+			headers_data.tree.as_ref(),
+			headers_data.expr_path.as_ref(),
+			headers_data.certificate_expression.as_ref(),
+	) {
+			(Some(Ok(certificate)), Some(Ok(tree)), Some(Ok(expr_path)), Some(expression)) => {
+					(certificate, tree, expr_path, expression)
+			}
+			_ => return Err(not_valid()),
+	};
+
+	let cert: Certificate = serde_cbor::from_slice(certificate)
+			.map_err(|e| ValidationError::Certificate(AgentError::InvalidCborData(e).to_string()))?;
+	let tree: HashTree = serde_cbor::from_slice(tree_bytes)
+			.map_err(|e| ValidationError::Body(AgentError::InvalidCborData(e).to_string()))?;
+	let expr_path: Vec<String> = serde_cbor::from_slice(expr_path)
+			.map_err(|e| ValidationError::Body(format!("Invalid expr_path: {}", e)))?;
+	let certificate_expression =
+			CertificateExpression::parse(expression).map_err(ValidationError::Body)?;
+
+	if !verify_certificate(&cert, &tree, canister_id, agent, config, logger)? {
+			return Err(not_valid());
+	}
+	validate_expr_path(&expr_path, request.url, &tree)?;
+
+	// The tree path of a response is `<expr_path>/<expr_hash>/<request_hash>/<response_hash>`,
+	// the request hash is empty without request certification. The response hash
+	// needs the hash of the body, it is added by `CertifiedBody::check_sha`.
+	let mut path: Vec<Vec<u8>> = expr_path.into_iter().map(String::into_bytes).collect();
+	path.push(expression_hash(expression).to_vec());
+	let certification = match certificate_expression.certification {
+			Some(certification) => certification,
+			None => {
+					let labels: Vec<TreeLabel> = path.iter().map(TreeLabel::from).collect();
+					if matches!(
+							tree.lookup_path(&labels),
+							LookupResult::Absent | LookupResult::Unknown
+					) {
+							slog::trace!(logger, ">> Invalid Tree in the header. Does not contain path {:?}", path);
+							return Err(not_valid());
+					}
+					return Ok(None);
+			}
+	};
+	let request_hash = certification.request.as_ref().map(|certification| {
+			request_hash(
+					certification,
+					request.method,
+					request.url,
+					request.headers,
+					request.body,
+			)
+	});
+	path.push(request_hash.map_or_else(Vec::new, |request_hash| request_hash.to_vec()));
+
+	Ok(Some(CertifiedBody::Response {
+			tree: tree_bytes.clone(),
+			path,
+			headers_hash: response_headers_hash(
+					&certification.response,
+					response.status_code,
+					response.headers,
+			),
+	}))
+}
+
+// The expression path must be the most specific path of the tree for the url:
+// the exact path ending with `<$>`, or a path ending with the `<*>` wildcard when
+// the tree proves that no more specific path exists.
+fn validate_expr_path(expr_path: &[String], url: &str, tree: &HashTree) -> Result<(), ValidationError> {
+	let invalid = || ValidationError::Body(format!("Invalid expr_path {:?} for {}", expr_path, url));
+	let path = certified_path(url);
+	let segments = path.split('/').skip(1).collect::<Vec<_>>();
+	let (last, prefix) = match expr_path.split_last() {
+			Some((last, [first, prefix @ ..])) if first == "http_expr" => (last, prefix),
+			_ => return Err(invalid()),
+	};
+	match last.as_str() {
+			"<$>" if prefix == segments.as_slice() => Ok(()),
+			"<*>" if prefix.len() <= segments.len() && prefix.iter().zip(&segments).all(|(a, b)| a == b) => {
+					let exact = [&segments[..], &["<$>"]].concat();
+					let wildcards = (prefix.len() + 1..=segments.len())
+							.rev()
+							.map(|len| [&segments[..len], &["<*>"]].concat());
+					for more_specific in std::iter::once(exact).chain(wildcards) {
+							let path: Vec<TreeLabel> = std::iter::once("http_expr")
+									.chain(more_specific)
+									.map(TreeLabel::from)
+									.collect();
+							if !matches!(tree.lookup_path(&path), LookupResult::Absent) {
+									return Err(invalid());
+							}
+					}
+					Ok(())
+			}
+			_ => Err(invalid()),
+	}
+}
+
+// The sha256 of the body decoded with its `Content-Encoding`.
+fn decode_body_to_sha256(
+	body: &[u8],
+	encoding: Option<&str>,
+	limits: DecompressionLimits,
+) -> Result<[u8; 32], String> {
+	let mut hasher = BodyHasher::new(encoding, limits)?;
+	hasher.update(body)?;
+	hasher.finish()
+}
+
+struct Certificates<'a> {
+	certificate: &'a Vec<u8>,
+	tree: &'a Vec<u8>,
+}
+
+// Verify the certificate and return the certified hash of the asset `tree_key`.
+fn certified_sha(
+	certificates: Certificates,
+	canister_id: &Principal,
+	agent: &Agent,
+	tree_key: String,
+	config: &ValidationConfig,
+	logger: &slog::Logger,
+) -> Result<Vec<u8>, ValidationError> {
+	let cert: Certificate = serde_cbor::from_slice(certificates.certificate)
+			.map_err(|e| ValidationError::Certificate(AgentError::InvalidCborData(e).to_string()))?;
+	let tree: HashTree = serde_cbor::from_slice(certificates.tree)
+			.map_err(|e| ValidationError::Body(AgentError::InvalidCborData(e).to_string()))?;
+
+	if !verify_certificate(&cert, &tree, canister_id, agent, config, logger)? {
+			return Err(not_valid());
+	}
+
 	let path = ["http_assets".into(), tree_key.into()];
 	let tree_sha = match tree.lookup_path(&path) {
 			LookupResult::Found(v) => v,
-			_ => match tree.lookup_path(&["http_assets".into(), "/index.html".into()]) {
+			// The asset canister answers an unknown path with `/index.html`, this is
+			// only accepted when the tree proves that the path is not certified.
+			LookupResult::Absent => match tree.lookup_path(&["http_assets".into(), "/index.html".into()]) {
 					LookupResult::Found(v) => v,
 					_ => {
 							slog::trace!(
 									logger,
-									">> Invalid Tree in the header. Does not contain path {:?}",
+									">> Invalid Tree in the header. Does not contain path {:?} nor /index.html",
 									path
 							);
-							return Ok(false);
+							return Err(not_valid());
 					}
 			},
+			_ => {
+					slog::trace!(
+							logger,
+							">> Invalid Tree in the header. Does not contain path {:?}",
+							path
+					);
+					return Err(not_valid());
+			}
 	};
 
-	Ok(body_sha == tree_sha)
+	Ok(tree_sha.to_vec())
 }
 
+// The certificate time is in nanoseconds since the epoch, encoded in unsigned leb128.
+fn decode_leb128(bytes: &[u8]) -> Option<u64> {
+	let mut value: u64 = 0;
+	for (i, byte) in bytes.iter().enumerate() {
+			if i >= 10 {
+					return None;
+			}
+			value |= u64::from(byte & 0x7f) << (7 * i);
+			if byte & 0x80 == 0 {
+					return Some(value);
+			}
+	}
+	None
+}
+
+fn check_certificate_time(
+	time: Duration,
+	now: SystemTime,
+	max_offset: Duration,
+) -> Result<(), ValidationError> {
+	let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+	if time + max_offset < now {
+			return Err(ValidationError::CertificateTime(format!(
+					"certificate is {}s old",
+					(now - time).as_secs()
+			)));
+	}
+	if time > now + max_offset {
+			return Err(ValidationError::CertificateTime(format!(
+					"certificate is {}s in the future",
+					(time - now).as_secs()
+			)));
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_decode_leb128() {
+		assert_eq!(Some(0), decode_leb128(&[0]));
+		assert_eq!(Some(624_485), decode_leb128(&[0xe5, 0x8e, 0x26]));
+		assert_eq!(
+			Some(1_650_000_000_000_000_000),
+			decode_leb128(&[0x80, 0x80, 0x94, 0xbb, 0xa0, 0xc8, 0xfe, 0xf2, 0x16])
+		);
+		assert_eq!(None, decode_leb128(&[0x80]));
+		assert_eq!(None, decode_leb128(&[]));
+	}
+
+	#[test]
+	fn test_certification_mode() {
+		let config = ValidationConfig::new(
+			Duration::from_secs(300),
+			"warn",
+			&["r5m5i-tiaaa-aaaaj-acgaq-cai:Strict".to_string()],
+			false,
+			RawAccess::Enabled,
+			DecompressionLimits::default(),
+			&[],
+		)
+		.unwrap();
+		let strict_canister = Principal::from_text("r5m5i-tiaaa-aaaaj-acgaq-cai").unwrap();
+		let other_canister = Principal::from_text("ngrpb-5qaaa-aaaaj-adz7a-cai").unwrap();
+		assert_eq!(CertificationMode::Strict, config.certification_mode(&strict_canister));
+		assert_eq!(CertificationMode::Warn, config.certification_mode(&other_canister));
+
+		let logger = slog::Logger::root(slog::Discard, slog::o!());
+		assert!(matches!(
+			uncertified(&strict_canister, &config, "response", &logger),
+			Err(ValidationError::Uncertified(_))
+		));
+		assert!(uncertified(&other_canister, &config, "response", &logger).is_ok());
+		assert!(config.certifies_whole_stream(&strict_canister, false));
+		assert!(!config.certifies_whole_stream(&other_canister, false));
+		assert!(config.certifies_whole_stream(&other_canister, true));
+
+		assert!(ValidationConfig::new(
+			Duration::from_secs(300),
+			"lenient",
+			&[],
+			false,
+			RawAccess::Enabled,
+			DecompressionLimits::default(),
+			&[],
+		)
+		.is_err());
+		assert!(ValidationConfig::new(
+			Duration::from_secs(300),
+			"strict",
+			&["r5m5i-tiaaa-aaaaj-acgaq-cai".to_string()],
+			false,
+			RawAccess::Enabled,
+			DecompressionLimits::default(),
+			&[],
+		)
+		.is_err());
+	}
+
+	#[test]
+	fn test_validate_chunk_strict() {
+		let canister_id = Principal::from_text("r5m5i-tiaaa-aaaaj-acgaq-cai").unwrap();
+		let agent = Agent::builder()
+			.with_transport(
+				ic_agent::agent::http_transport::ReqwestHttpReplicaV2Transport::create("http://localhost:8000")
+					.unwrap(),
+			)
+			.build()
+			.unwrap();
+		let config = ValidationConfig::new(
+			Duration::from_secs(300),
+			"strict",
+			&[],
+			false,
+			RawAccess::Enabled,
+			DecompressionLimits::default(),
+			&[],
+		)
+		.unwrap();
+		let logger = slog::Logger::root(slog::Discard, slog::o!());
+		let chunk = |token| StreamingCallbackHttpResponse {
+			body: vec![1; 10],
+			token,
+		};
+
+		//the last chunk, without token, has the verification of the stream.
+		assert_eq!(
+			Ok(Verification::CertifiedV1),
+			validate_chunk(chunk(None), canister_id, &agent, "/", &Verification::CertifiedV1, &config, logger.clone())
+				.map_err(|err| err.to_string())
+		);
+		//a chunk with a token without certification is rejected.
+		let token = Token(IDLValue::Record(vec![candid::parser::value::IDLField {
+			id: Label::Named("index".to_string()),
+			val: IDLValue::Nat64(1),
+		}]));
+		assert!(matches!(
+			validate_chunk(chunk(Some(token)), canister_id, &agent, "/", &Verification::CertifiedV1, &config, logger.clone()),
+			Err(ValidationError::Uncertified(_))
+		));
+		//a certificate that isn't base64 is rejected.
+		let field = |id, val: &str| IDLField {
+			id: Label::Id(id),
+			val: IDLValue::Text(val.to_string()),
+		};
+		let token = Token(IDLValue::Record(vec![
+			field(1_102_915_300, "not base64!"),
+			field(1_292_081_502, "2dn3gwGDAktodHRwX2Fzc2V0cw"),
+			field(3_577_787_238, "/"),
+		]));
+		assert!(matches!(
+			validate_chunk(chunk(Some(token)), canister_id, &agent, "/", &Verification::CertifiedV1, &config, logger),
+			Err(ValidationError::Certificate(_))
+		));
+	}
+
+	#[test]
+	fn test_raw_access() {
+		let allowed = Principal::from_text("r5m5i-tiaaa-aaaaj-acgaq-cai").unwrap();
+		let other = Principal::from_text("ngrpb-5qaaa-aaaaj-adz7a-cai").unwrap();
+		let config = |raw_access| {
+			ValidationConfig::new(
+				Duration::from_secs(300),
+				"strict",
+				&[],
+				false,
+				raw_access,
+				DecompressionLimits::default(),
+				&[],
+			)
+			.unwrap()
+		};
+
+		let enabled = config(RawAccess::new(false, &[]).unwrap());
+		assert!(enabled.allows_raw(&allowed) && enabled.allows_raw(&other));
+		let disabled = config(RawAccess::new(true, &["r5m5i-tiaaa-aaaaj-acgaq-cai".to_string()]).unwrap());
+		assert!(!disabled.allows_raw(&allowed) && !disabled.allows_raw(&other));
+		let allowlist = config(RawAccess::new(false, &["r5m5i-tiaaa-aaaaj-acgaq-cai".to_string()]).unwrap());
+		assert!(allowlist.allows_raw(&allowed) && !allowlist.allows_raw(&other));
+		assert!(RawAccess::new(false, &["r5m5i".to_string()]).is_err());
+	}
+
+	#[test]
+	fn test_decompression_limits() {
+		let global = DecompressionLimits {
+			max_size: None,
+			max_ratio: Some(200),
+		};
+		let config = ValidationConfig::new(
+			Duration::from_secs(300),
+			"permissive",
+			&[],
+			false,
+			RawAccess::Enabled,
+			global,
+			&[
+				"r5m5i-tiaaa-aaaaj-acgaq-cai:104857600:".to_string(),
+				"ngrpb-5qaaa-aaaaj-adz7a-cai::0".to_string(),
+			],
+		)
+		.unwrap();
+		let canister = |id| Principal::from_text(id).unwrap();
+		assert_eq!(
+			DecompressionLimits {
+				max_size: Some(104_857_600),
+				max_ratio: Some(200),
+			},
+			config.decompression_limits(&canister("r5m5i-tiaaa-aaaaj-acgaq-cai"))
+		);
+		assert_eq!(
+			DecompressionLimits::default(),
+			config.decompression_limits(&canister("ngrpb-5qaaa-aaaaj-adz7a-cai"))
+		);
+		assert_eq!(global, config.decompression_limits(&canister("aaaaa-aa")));
+
+		assert!(parse_canister_decompression_limits("r5m5i-tiaaa-aaaaj-acgaq-cai:100", global).is_err());
+		assert!(parse_canister_decompression_limits("r5m5i-tiaaa-aaaaj-acgaq-cai:big:", global).is_err());
+	}
+
+	#[test]
+	fn test_certified_path() {
+		assert_eq!("/-/uefa_nfts4g_0", certified_path("/-/uefa_nfts4g_0?query=owner"));
+		assert_eq!("/", certified_path("/"));
+		assert_eq!("/my file.png", certified_path("/my%20file.png#top"));
+		assert_eq!("/100%", certified_path("/100%25"));
+	}
+
+	#[test]
+	fn test_check_certificate_time() {
+		let now = UNIX_EPOCH + Duration::from_secs(1_650_000_000);
+		let max_offset = Duration::from_secs(300);
+		assert!(check_certificate_time(Duration::from_secs(1_650_000_000), now, max_offset).is_ok());
+		assert!(check_certificate_time(Duration::from_secs(1_649_999_800), now, max_offset).is_ok());
+		assert!(matches!(
+			check_certificate_time(Duration::from_secs(1_649_999_000), now, max_offset),
+			Err(ValidationError::CertificateTime(_))
+		));
+		assert!(matches!(
+			check_certificate_time(Duration::from_secs(1_650_001_000), now, max_offset),
+			Err(ValidationError::CertificateTime(_))
+		));
+	}
+}
@@ -0,0 +1,698 @@
+use crate::req_validation::Verification;
+use hyper::{header, HeaderMap};
+use ic_agent::export::Principal;
+use ic_utils::interfaces::http_request::HeaderField;
+use serde_cbor::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// A verified canister response, as returned by the canister.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub verification: Verification,
+}
+
+impl CachedResponse {
+    pub fn new(
+        status_code: u16,
+        headers: &[HeaderField],
+        body: &[u8],
+        verification: Verification,
+    ) -> Self {
+        CachedResponse {
+            status_code,
+            headers: headers
+                .iter()
+                .map(|HeaderField(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: body.to_vec(),
+            verification,
+        }
+    }
+
+    pub fn header_fields(&self) -> Vec<HeaderField<'static>> {
+        self.headers
+            .iter()
+            .map(|(name, value)| HeaderField(name.clone().into(), value.clone().into()))
+            .collect()
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn size(&self) -> usize {
+        self.body.len()
+            + self
+                .headers
+                .iter()
+                .map(|(name, value)| name.len() + value.len())
+                .sum::<usize>()
+    }
+}
+
+/// In-process cache of the verified canister responses, keyed on the canister id, the url
+/// sent to the canister and the request headers of the `Vary` response header. The
+/// responses are fresh for their `Cache-Control` or `Expires` lifetime, then kept `max_stale`
+/// longer to be served when the canister can't be reached, with a LRU eviction when the
+/// size of the cached responses is over the maximum size.
+pub struct ResponseCache {
+    entries: Mutex<ResponseCacheEntries>,
+    max_size: usize,
+    max_stale: Duration,
+}
+
+#[derive(Default)]
+struct ResponseCacheEntries {
+    //the variants of the responses of a canister url.
+    map: HashMap<String, Vec<ResponseCacheEntry>>,
+    //the key of each entry by its last access, from the least recently used.
+    lru: BTreeMap<u64, String>,
+    size: usize,
+    //incremented at each access, used to find the least recently used entry.
+    tick: u64,
+}
+
+struct ResponseCacheEntry {
+    response: Arc<CachedResponse>,
+    //the request headers of the variant.
+    vary: Vec<(String, String)>,
+    stored_at: Instant,
+    expire_at: Instant,
+    //the end of the time the response can be served stale.
+    keep_until: Instant,
+    last_used: u64,
+}
+
+/// A response found in the cache, `age` is the time since it was stored.
+pub struct CacheHit {
+    pub response: Arc<CachedResponse>,
+    pub age: Duration,
+}
+
+impl ResponseCache {
+    /// A max size of 0 disables the cache.
+    pub fn new(max_size: usize, max_stale: Duration) -> Self {
+        ResponseCache {
+            entries: Mutex::new(ResponseCacheEntries::default()),
+            max_size,
+            max_stale,
+        }
+    }
+
+    /// How long the expired responses are kept.
+    pub fn max_stale(&self) -> Duration {
+        self.max_stale
+    }
+
+    /// A fresh response.
+    pub fn get(
+        &self,
+        canister_id: &Principal,
+        url: &str,
+        request_headers: &HeaderMap,
+    ) -> Option<CacheHit> {
+        self.find(canister_id, url, request_headers, false, Instant::now())
+    }
+
+    /// A response, fresh or expired for less than `max_stale`.
+    pub fn get_stale(
+        &self,
+        canister_id: &Principal,
+        url: &str,
+        request_headers: &HeaderMap,
+    ) -> Option<CacheHit> {
+        self.find(canister_id, url, request_headers, true, Instant::now())
+    }
+
+    fn find(
+        &self,
+        canister_id: &Principal,
+        url: &str,
+        request_headers: &HeaderMap,
+        stale: bool,
+        now: Instant,
+    ) -> Option<CacheHit> {
+        if self.max_size == 0 {
+            return None;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.tick += 1;
+        let tick = entries.tick;
+        let key = cache_key(canister_id, url);
+        let ResponseCacheEntries { map, lru, .. } = &mut *entries;
+        let variants = map.get_mut(&key)?;
+        let entry = variants.iter_mut().find(|entry| {
+            entry
+                .vary
+                .iter()
+                .all(|(name, value)| request_header(request_headers, name) == *value)
+        })?;
+        if entry.keep_until <= now {
+            entries.remove(&key, now);
+            return None;
+        }
+        if entry.expire_at <= now && !stale {
+            return None;
+        }
+        lru.remove(&entry.last_used);
+        lru.insert(tick, key);
+        entry.last_used = tick;
+        Some(CacheHit {
+            response: entry.response.clone(),
+            age: now - entry.stored_at,
+        })
+    }
+
+    /// Store a response if it can be cached: certified, with a freshness lifetime and
+    /// without `Vary: *`.
+    pub fn insert(
+        &self,
+        canister_id: &Principal,
+        url: &str,
+        request_headers: &HeaderMap,
+        response: CachedResponse,
+    ) {
+        let size = response.size();
+        if size > self.max_size {
+            return;
+        }
+        let (lifetime, vary) = match cacheable(&response, request_headers) {
+            Some(cacheable) => cacheable,
+            None => return,
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        let key = cache_key(canister_id, url);
+        if let Some(variants) = entries.map.get_mut(&key) {
+            if let Some(index) = variants.iter().position(|entry| entry.vary == vary) {
+                let entry = variants.remove(index);
+                entries.size -= entry.response.size();
+                entries.lru.remove(&entry.last_used);
+            }
+        }
+        if entries.size + size > self.max_size {
+            entries.evict(self.max_size - size);
+        }
+        entries.tick += 1;
+        let entry = ResponseCacheEntry {
+            response: Arc::new(response),
+            vary,
+            stored_at: now,
+            expire_at: now + lifetime,
+            keep_until: now + lifetime + self.max_stale,
+            last_used: entries.tick,
+        };
+        entries.size += size;
+        let tick = entries.tick;
+        entries.lru.insert(tick, key.clone());
+        entries.map.entry(key).or_default().push(entry);
+    }
+}
+
+impl ResponseCacheEntries {
+    // Remove the variants of a key that can't be served, even stale.
+    fn remove(&mut self, key: &str, now: Instant) {
+        if let Some(variants) = self.map.get_mut(key) {
+            let (size, lru) = (&mut self.size, &mut self.lru);
+            variants.retain(|entry| {
+                let expired = entry.keep_until <= now;
+                if expired {
+                    *size -= entry.response.size();
+                    lru.remove(&entry.last_used);
+                }
+                !expired
+            });
+            if variants.is_empty() {
+                self.map.remove(key);
+            }
+        }
+    }
+
+    // Remove the least recently used entries until the size of the cached responses is at
+    // most `max_size`.
+    fn evict(&mut self, max_size: usize) {
+        while self.size > max_size {
+            let (tick, key) = match self.lru.iter().next() {
+                Some((tick, key)) => (*tick, key.clone()),
+                None => break,
+            };
+            self.lru.remove(&tick);
+            if let Some(variants) = self.map.get_mut(&key) {
+                if let Some(index) = variants.iter().position(|entry| entry.last_used == tick) {
+                    let entry = variants.remove(index);
+                    self.size -= entry.response.size();
+                }
+                if variants.is_empty() {
+                    self.map.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+/// The Redis key and value of a response that can be cached, and the time to live of the
+/// value in seconds: the response is kept until it's expired for `max_stale`. The value is
+/// a CBOR array of the status code, the headers, the body, the verification, the times
+/// the response was stored and expires, and the request headers of its `Vary` header.
+/// The key only has the accepted encodings, the responses varying on other request
+/// headers are not stored in Redis.
+pub fn redis_entry(
+    canister_id: &Principal,
+    url: &str,
+    request_headers: &HeaderMap,
+    response: &CachedResponse,
+    max_stale: Duration,
+) -> Option<(String, Vec<u8>, usize)> {
+    let (lifetime, vary) = cacheable(response, request_headers)?;
+    if vary
+        .iter()
+        .any(|(name, _)| name != header::ACCEPT_ENCODING.as_str())
+    {
+        return None;
+    }
+    let now = unix_time(SystemTime::now());
+    let value = Value::Array(vec![
+        Value::Integer(response.status_code.into()),
+        headers_value(&response.headers),
+        Value::Bytes(response.body.clone()),
+        Value::Text(response.verification.header_value().to_string()),
+        Value::Integer(now.into()),
+        Value::Integer((now + lifetime.as_secs()).into()),
+        headers_value(&vary),
+    ]);
+    Some((
+        redis_key(canister_id, url, request_headers),
+        serde_cbor::to_vec(&value).ok()?,
+        (lifetime + max_stale).as_secs() as usize,
+    ))
+}
+
+/// The Redis key of the response to a request.
+pub fn redis_key(canister_id: &Principal, url: &str, request_headers: &HeaderMap) -> String {
+    format!(
+        "response:{}\n{}",
+        cache_key(canister_id, url),
+        request_header(request_headers, header::ACCEPT_ENCODING.as_str())
+    )
+}
+
+/// A response read from Redis, fresh or expired for less than `max_stale`, when the request
+/// has the headers of its `Vary` header.
+pub fn from_redis_entry(
+    value: &[u8],
+    request_headers: &HeaderMap,
+    max_stale: Duration,
+) -> Option<CacheHit> {
+    let values = match serde_cbor::from_slice(value).ok()? {
+        Value::Array(values) => values,
+        _ => return None,
+    };
+    match values.as_slice() {
+        [Value::Integer(status_code), headers, Value::Bytes(body), Value::Text(verification), Value::Integer(stored_at), Value::Integer(expire_at), vary] =>
+        {
+            let matches = headers_from_value(vary)?
+                .iter()
+                .all(|(name, value)| request_header(request_headers, name) == *value);
+            let now = i128::from(unix_time(SystemTime::now()));
+            if !matches || now >= expire_at + i128::from(max_stale.as_secs()) {
+                return None;
+            }
+            let verification = match verification.as_str() {
+                "certified-v1" => Verification::CertifiedV1,
+                "certified-v2" => Verification::CertifiedV2,
+                _ => return None,
+            };
+            let response = CachedResponse {
+                status_code: u16::try_from(*status_code).ok()?,
+                headers: headers_from_value(headers)?,
+                body: body.clone(),
+                verification,
+            };
+            Some(CacheHit {
+                response: Arc::new(response),
+                age: Duration::from_secs(u64::try_from(now - stored_at).unwrap_or_default()),
+            })
+        }
+        _ => None,
+    }
+}
+
+fn headers_value(headers: &[(String, String)]) -> Value {
+    Value::Array(
+        headers
+            .iter()
+            .map(|(name, value)| {
+                Value::Array(vec![Value::Text(name.clone()), Value::Text(value.clone())])
+            })
+            .collect(),
+    )
+}
+
+fn headers_from_value(value: &Value) -> Option<Vec<(String, String)>> {
+    match value {
+        Value::Array(headers) => headers
+            .iter()
+            .map(|header| match header {
+                Value::Array(header) => match header.as_slice() {
+                    [Value::Text(name), Value::Text(value)] => Some((name.clone(), value.clone())),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+// The freshness lifetime and the request headers of the `Vary` header of a response that
+// can be cached.
+fn cacheable(
+    response: &CachedResponse,
+    request_headers: &HeaderMap,
+) -> Option<(Duration, Vec<(String, String)>)> {
+    if !matches!(
+        response.verification,
+        Verification::CertifiedV1 | Verification::CertifiedV2
+    ) {
+        return None;
+    }
+    let lifetime = freshness_lifetime(response, SystemTime::now())?;
+    Some((lifetime, vary(response, request_headers)?))
+}
+
+fn cache_key(canister_id: &Principal, url: &str) -> String {
+    format!("{}{}", canister_id, url)
+}
+
+// The values of a request header, joined like in a single header.
+fn request_header(request_headers: &HeaderMap, name: &str) -> String {
+    request_headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// The request headers of the `Vary` header of the response, `None` for `Vary: *`.
+// The asset canister chooses the encoding of the body from `Accept-Encoding` without
+// a `Vary` header, so responses always vary on it.
+fn vary(response: &CachedResponse, request_headers: &HeaderMap) -> Option<Vec<(String, String)>> {
+    let mut names = vec![header::ACCEPT_ENCODING.as_str().to_string()];
+    for (name, value) in &response.headers {
+        if !name.eq_ignore_ascii_case(header::VARY.as_str()) {
+            continue;
+        }
+        for name in value
+            .split(',')
+            .map(|name| name.trim().to_ascii_lowercase())
+        {
+            if name == "*" {
+                return None;
+            }
+            if !name.is_empty() && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    Some(
+        names
+            .into_iter()
+            .map(|name| {
+                let value = request_header(request_headers, &name);
+                (name, value)
+            })
+            .collect(),
+    )
+}
+
+// How long a response can be served from the cache: the `s-maxage` or `max-age` of
+// `Cache-Control`, or the `Expires` date. `None` when the response can't be cached.
+fn freshness_lifetime(response: &CachedResponse, now: SystemTime) -> Option<Duration> {
+    let cache_control = response
+        .headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case(header::CACHE_CONTROL.as_str()))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    let max_age = |name: &str| {
+        cache_control.iter().find_map(|directive| {
+            let (directive, value) = directive.split_once('=')?;
+            if directive.trim() == name {
+                value.trim().trim_matches('"').parse::<u64>().ok()
+            } else {
+                None
+            }
+        })
+    };
+    if cache_control
+        .iter()
+        .any(|directive| matches!(directive.as_str(), "no-store" | "no-cache" | "private"))
+    {
+        return None;
+    }
+    let lifetime = match max_age("s-maxage").or_else(|| max_age("max-age")) {
+        Some(max_age) => Duration::from_secs(max_age),
+        None => {
+            let expires = httpdate::parse_http_date(response.header("expires")?).ok()?;
+            let date = response
+                .header("date")
+                .and_then(|date| httpdate::parse_http_date(date).ok())
+                .unwrap_or(now);
+            expires.duration_since(date).ok()?
+        }
+    };
+    if lifetime.is_zero() {
+        None
+    } else {
+        Some(lifetime)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn response(headers: &[(&str, &str)], body: &[u8]) -> CachedResponse {
+        CachedResponse {
+            status_code: 200,
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: body.to_vec(),
+            verification: Verification::CertifiedV1,
+        }
+    }
+
+    fn canister() -> Principal {
+        Principal::from_text("r5m5i-tiaaa-aaaaj-acgaq-cai").unwrap()
+    }
+
+    #[test]
+    fn test_freshness_lifetime() {
+        let now = SystemTime::now();
+        let lifetime = |headers: &[(&str, &str)]| freshness_lifetime(&response(headers, b""), now);
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            lifetime(&[("Cache-Control", "public, max-age=60")])
+        );
+        assert_eq!(
+            Some(Duration::from_secs(10)),
+            lifetime(&[("Cache-Control", "max-age=60, s-maxage=10")])
+        );
+        assert_eq!(None, lifetime(&[("Cache-Control", "max-age=0")]));
+        assert_eq!(None, lifetime(&[("Cache-Control", "no-store, max-age=60")]));
+        assert_eq!(None, lifetime(&[("Cache-Control", "private, max-age=60")]));
+        assert_eq!(None, lifetime(&[]));
+        assert_eq!(
+            Some(Duration::from_secs(3600)),
+            lifetime(&[
+                ("Date", "Wed, 21 Oct 2015 07:28:00 GMT"),
+                ("Expires", "Wed, 21 Oct 2015 08:28:00 GMT"),
+            ])
+        );
+        assert_eq!(
+            None,
+            lifetime(&[("Expires", "Wed, 21 Oct 2015 08:28:00 GMT")])
+        );
+        assert_eq!(None, lifetime(&[("Expires", "0")]));
+    }
+
+    #[test]
+    fn test_response_cache() {
+        let cache = ResponseCache::new(1000, Duration::ZERO);
+        let mut gzip = HeaderMap::new();
+        gzip.insert(header::ACCEPT_ENCODING, "gzip".parse().unwrap());
+        let cached = response(&[("Cache-Control", "max-age=60")], b"hello");
+        cache.insert(&canister(), "/index.html", &gzip, cached.clone());
+        assert_eq!(
+            Some(cached),
+            cache
+                .get(&canister(), "/index.html", &gzip)
+                .map(|hit| (*hit.response).clone())
+        );
+        assert!(cache.get(&canister(), "/other.html", &gzip).is_none());
+        //the response varies on the accepted encodings.
+        assert!(cache
+            .get(&canister(), "/index.html", &HeaderMap::new())
+            .is_none());
+
+        //uncertified and not cacheable responses are not stored.
+        let mut uncertified = response(&[("Cache-Control", "max-age=60")], b"hello");
+        uncertified.verification = Verification::Skipped;
+        cache.insert(&canister(), "/raw.html", &gzip, uncertified);
+        assert!(cache.get(&canister(), "/raw.html", &gzip).is_none());
+        cache.insert(&canister(), "/new.html", &gzip, response(&[], b"hello"));
+        assert!(cache.get(&canister(), "/new.html", &gzip).is_none());
+        let any = response(&[("Cache-Control", "max-age=60"), ("Vary", "*")], b"hello");
+        cache.insert(&canister(), "/any.html", &gzip, any);
+        assert!(cache.get(&canister(), "/any.html", &gzip).is_none());
+    }
+
+    #[test]
+    fn test_response_cache_stale() {
+        let cache = ResponseCache::new(1000, Duration::from_secs(60));
+        let headers = HeaderMap::new();
+        let expired = response(
+            &[
+                ("Date", "Wed, 21 Oct 2015 07:28:00 GMT"),
+                ("Expires", "Wed, 21 Oct 2015 07:28:01 GMT"),
+            ],
+            b"hello",
+        );
+        cache.insert(&canister(), "/", &headers, expired);
+        //an expired response is only served stale.
+        let later = Instant::now() + Duration::from_secs(2);
+        assert!(cache
+            .find(&canister(), "/", &headers, false, later)
+            .is_none());
+        assert_eq!(
+            b"hello".to_vec(),
+            cache
+                .find(&canister(), "/", &headers, true, later)
+                .unwrap()
+                .response
+                .body
+        );
+        //it's removed once it can't be served stale.
+        let later = later + Duration::from_secs(60);
+        assert!(cache
+            .find(&canister(), "/", &headers, true, later)
+            .is_none());
+        assert!(cache.get_stale(&canister(), "/", &headers).is_none());
+    }
+
+    #[test]
+    fn test_redis_entry() {
+        let mut gzip = HeaderMap::new();
+        gzip.insert(header::ACCEPT_ENCODING, "gzip".parse().unwrap());
+        let cached = response(&[("Cache-Control", "max-age=60")], b"hello");
+        let (key, value, ttl) =
+            redis_entry(&canister(), "/", &gzip, &cached, Duration::from_secs(3600)).unwrap();
+        assert_eq!(redis_key(&canister(), "/", &gzip), key);
+        assert_eq!(3660, ttl);
+        let hit = from_redis_entry(&value, &gzip, Duration::from_secs(3600)).unwrap();
+        assert_eq!(cached, *hit.response);
+        //the response varies on the accepted encodings.
+        assert!(from_redis_entry(&value, &HeaderMap::new(), Duration::from_secs(3600)).is_none());
+
+        let mut uncertified = cached;
+        uncertified.verification = Verification::Uncertified;
+        assert!(redis_entry(&canister(), "/", &gzip, &uncertified, Duration::ZERO).is_none());
+        //the key has only the accepted encodings.
+        let language = response(
+            &[("Cache-Control", "max-age=60"), ("Vary", "Accept-Language")],
+            b"hello",
+        );
+        assert!(redis_entry(&canister(), "/", &gzip, &language, Duration::ZERO).is_none());
+    }
+
+    #[test]
+    fn test_response_cache_vary() {
+        let cache = ResponseCache::new(1000, Duration::ZERO);
+        let headers = [("Cache-Control", "max-age=60"), ("Vary", "Accept-Language")];
+        let mut english = HeaderMap::new();
+        english.insert(header::ACCEPT_LANGUAGE, "en".parse().unwrap());
+        let mut french = HeaderMap::new();
+        french.insert(header::ACCEPT_LANGUAGE, "fr".parse().unwrap());
+        cache.insert(&canister(), "/", &english, response(&headers, b"hello"));
+        cache.insert(&canister(), "/", &french, response(&headers, b"bonjour"));
+        assert_eq!(
+            b"hello".to_vec(),
+            cache.get(&canister(), "/", &english).unwrap().response.body
+        );
+        assert_eq!(
+            b"bonjour".to_vec(),
+            cache.get(&canister(), "/", &french).unwrap().response.body
+        );
+    }
+
+    #[test]
+    fn test_response_cache_eviction() {
+        let headers = [("Cache-Control", "max-age=60")];
+        let size = response(&headers, &[0; 100]).size();
+        let cache = ResponseCache::new(2 * size, Duration::ZERO);
+        let request_headers = HeaderMap::new();
+        cache.insert(
+            &canister(),
+            "/a",
+            &request_headers,
+            response(&headers, &[0; 100]),
+        );
+        cache.insert(
+            &canister(),
+            "/b",
+            &request_headers,
+            response(&headers, &[0; 100]),
+        );
+        cache.get(&canister(), "/a", &request_headers).unwrap();
+        //the least recently used response is evicted.
+        cache.insert(
+            &canister(),
+            "/c",
+            &request_headers,
+            response(&headers, &[0; 100]),
+        );
+        assert!(cache.get(&canister(), "/a", &request_headers).is_some());
+        assert!(cache.get(&canister(), "/b", &request_headers).is_none());
+        assert!(cache.get(&canister(), "/c", &request_headers).is_some());
+
+        //a response larger than the cache is not stored.
+        cache.insert(
+            &canister(),
+            "/d",
+            &request_headers,
+            response(&headers, &[0; 1000]),
+        );
+        assert!(cache.get(&canister(), "/d", &request_headers).is_none());
+        assert!(cache.get(&canister(), "/c", &request_headers).is_some());
+
+        let cache = ResponseCache::new(0, Duration::ZERO);
+        cache.insert(
+            &canister(),
+            "/a",
+            &request_headers,
+            response(&headers, &[0; 1]),
+        );
+        assert!(cache.get(&canister(), "/a", &request_headers).is_none());
+    }
+}